@@ -4,8 +4,7 @@
 //! Using the UIGrid function for a prettier interface.
 
 extern crate iui;
-use forward::auth::Authentication;
-use forward::server::{ForwardServer, ForwardServerConfig};
+use forward::server::{ForwardServer, ForwardServerConfig, Upstream};
 use forward::target_addr::TargetAddr;
 use forward::target_addr::ToTargetAddr;
 use forward::tokio;
@@ -47,11 +46,11 @@ async fn main() {
     let mut group = Group::new(&ui, "Start New Server");
 
     let bind = entry_with_label(&ui, "Local Bind Address", Some("127.0.0.1:25565"));
-    let proxy = entry_with_label(&ui, "Through Proxy (Socks5)", Some("127.0.0.1:1080"));
+    let proxy = entry_with_label(&ui, "Through Proxy (URL)", Some("socks5h://127.0.0.1:1080"));
     let target = entry_with_label(&ui, "Target", Some("mc.hypixel.net"));
 
     let mut value_bind: Option<SocketAddr> = bind.1.value(&ui).parse().ok();
-    let mut value_proxy: Option<TargetAddr> = proxy.1.value(&ui).as_str().to_target_addr().ok();
+    let mut value_proxy: Option<Upstream> = proxy.1.value(&ui).parse().ok();
     let mut value_target: Option<TargetAddr> = target.1.value(&ui).as_str().to_target_addr().ok();
 
     let mut start_button = Button::new(&ui, "Start");
@@ -83,7 +82,7 @@ async fn main() {
             clicked = 1; // started
 
             value_bind = bind.value(&ui).parse().ok();
-            value_proxy = proxy.value(&ui).as_str().to_target_addr().ok();
+            value_proxy = proxy.value(&ui).parse().ok();
             value_target = target.value(&ui).as_str().to_target_addr().ok();
 
             if let (Some(value_bind), Some(value_proxy), Some(value_target)) =
@@ -92,14 +91,11 @@ async fn main() {
                 let mut rx = tx.subscribe();
 
                 tokio::spawn(async move {
-                    let proxy_auth = Authentication::None;
-
-                    let mut server = ForwardServer::new(ForwardServerConfig {
-                        bind_addr: value_bind,
-                        proxy: value_proxy,
-                        proxy_auth: proxy_auth,
-                        target: value_target,
-                    });
+                    let mut server = ForwardServer::new(ForwardServerConfig::new(
+                        value_bind,
+                        vec![value_proxy],
+                        vec![value_target],
+                    ));
 
                     server
                         .start(Some(tokio::spawn(async move {
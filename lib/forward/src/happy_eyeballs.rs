@@ -0,0 +1,134 @@
+//! Connection racing of IPv6 and IPv4 addresses ("Happy Eyeballs",
+//! RFC 8305), so that a dead address of a dual-stack host does not cost a
+//! full connect timeout.
+
+use futures::future::{pending, FutureExt};
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::{pin_mut, select};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{lookup_host, TcpStream, ToSocketAddrs};
+use tokio::time::delay_for;
+
+/// Time before the next address is tried while earlier attempts are still
+/// pending, the value recommended by RFC 8305.
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Opens a TCP connection to the first address of `addr` that answers.
+///
+/// The resolved addresses are tried alternating between address families,
+/// starting with the family of the first one. Each attempt gets a
+/// `CONNECTION_ATTEMPT_DELAY` head start over the next, which starts at once
+/// if it fails. The connection that completes first wins.
+pub async fn connect<T: ToSocketAddrs>(addr: T) -> io::Result<TcpStream> {
+    let addrs = interleave(lookup_host(addr).await?.collect());
+    connect_addrs(addrs, CONNECTION_ATTEMPT_DELAY).await
+}
+
+/// Races connections to `addrs`, in order, started `delay` apart.
+pub async fn connect_addrs(addrs: Vec<SocketAddr>, delay: Duration) -> io::Result<TcpStream> {
+    let mut addrs = addrs.into_iter().peekable();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    loop {
+        if attempts.is_empty() {
+            match addrs.next() {
+                Some(addr) => attempts.push(TcpStream::connect(addr)),
+                None => break,
+            }
+        }
+
+        let more = addrs.peek().is_some();
+        let stagger = async {
+            if more {
+                delay_for(delay).await
+            } else {
+                pending().await
+            }
+        }
+        .fuse();
+        pin_mut!(stagger);
+
+        select! {
+            result = attempts.select_next_some() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(error) => {
+                    last_error = Some(error);
+                    // An attempt that fails gives up its head start.
+                    if let Some(addr) = addrs.next() {
+                        attempts.push(TcpStream::connect(addr));
+                    }
+                }
+            },
+            _ = stagger => {
+                if let Some(addr) = addrs.next() {
+                    attempts.push(TcpStream::connect(addr));
+                }
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to")
+    }))
+}
+
+/// Orders `addrs` alternating between address families, starting with the
+/// family of the first address. The order within a family is kept.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_ipv6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return addrs,
+    };
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_ipv6);
+
+    let mut ordered = Vec::with_capacity(preferred.len() + other.len());
+    let mut other = other.into_iter();
+    for addr in preferred {
+        ordered.push(addr);
+        ordered.extend(other.next());
+    }
+    ordered.extend(other);
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn families_alternate() {
+        let v6 = |port| SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], port));
+        let v4 = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        assert_eq!(
+            interleave(vec![v6(1), v6(2), v6(3), v4(4), v4(5)]),
+            vec![v6(1), v4(4), v6(2), v4(5), v6(3)]
+        );
+        assert_eq!(
+            interleave(vec![v4(1), v4(2), v6(3), v6(4), v6(5)]),
+            vec![v4(1), v6(3), v4(2), v6(4), v6(5)]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_address_is_skipped() {
+        // Nothing listens on a port that was just released.
+        let refused = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+
+        let stream = connect_addrs(vec![refused, open], Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), open);
+    }
+}
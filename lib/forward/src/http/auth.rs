@@ -0,0 +1,278 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Builds the value of a `Proxy-Authorization` header using the Basic scheme.
+pub fn basic_authorization(username: &str, password: &str) -> String {
+    format!(
+        "Basic {}",
+        base64::encode(&format!("{}:{}", username, password))
+    )
+}
+
+/// Returns whether the value of a `Proxy-Authenticate` header is a Basic
+/// challenge.
+pub fn is_basic_challenge(header: &str) -> bool {
+    let header = header.trim().as_bytes();
+    header.len() >= 5
+        && header[..5].eq_ignore_ascii_case(b"basic")
+        && matches!(header.get(5), None | Some(b' '))
+}
+
+/// A `Proxy-Authenticate: Digest ...` challenge.
+#[derive(Debug, PartialEq, Default)]
+pub struct DigestChallenge {
+    pub realm: String,
+    pub nonce: String,
+    pub opaque: Option<String>,
+    pub algorithm: Option<String>,
+    pub qop: Option<String>,
+}
+
+impl DigestChallenge {
+    /// Parses the value of a `Proxy-Authenticate` header.
+    ///
+    /// Returns `None` if the header is not a Digest challenge or uses an
+    /// algorithm other than MD5 / MD5-sess.
+    pub fn parse(header: &str) -> Option<DigestChallenge> {
+        let header = header.trim();
+        if !header
+            .get(..7)
+            .is_some_and(|p| p.eq_ignore_ascii_case("digest "))
+        {
+            return None;
+        }
+
+        let mut challenge = DigestChallenge::default();
+        let mut nonce = None;
+        for (name, value) in split_params(&header[7..]) {
+            match name.to_ascii_lowercase().as_str() {
+                "realm" => challenge.realm = value,
+                "nonce" => nonce = Some(value),
+                "opaque" => challenge.opaque = Some(value),
+                "algorithm" => challenge.algorithm = Some(value),
+                "qop" => challenge.qop = Some(value),
+                _ => {}
+            }
+        }
+        challenge.nonce = nonce?;
+
+        match challenge.algorithm.as_ref() {
+            None => {}
+            Some(a) if a.eq_ignore_ascii_case("md5") || a.eq_ignore_ascii_case("md5-sess") => {}
+            Some(_) => return None,
+        }
+
+        Some(challenge)
+    }
+
+    fn is_sess(&self) -> bool {
+        match &self.algorithm {
+            Some(a) => a.eq_ignore_ascii_case("md5-sess"),
+            None => false,
+        }
+    }
+
+    fn supports_qop_auth(&self) -> bool {
+        match &self.qop {
+            Some(qop) => qop.split(',').any(|q| q.trim() == "auth"),
+            None => false,
+        }
+    }
+}
+
+/// Splits `a=b, c="d, e"` into name-value pairs, removing quotes.
+fn split_params(s: &str) -> Vec<(String, String)> {
+    let mut params = vec![];
+    let mut chars = s.chars().peekable();
+    loop {
+        while let Some(c) = chars.peek() {
+            if *c == ',' || c.is_whitespace() {
+                chars.next();
+            } else {
+                break;
+            }
+        }
+
+        let mut name = String::new();
+        for c in &mut chars {
+            if c == '=' {
+                break;
+            }
+            name.push(c);
+        }
+        if name.is_empty() {
+            break;
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => {
+                        if let Some(c) = chars.next() {
+                            value.push(c)
+                        }
+                    }
+                    c => value.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.peek() {
+                if *c == ',' {
+                    break;
+                }
+                value.push(*c);
+                chars.next();
+            }
+        }
+
+        params.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    params
+}
+
+/// Builds the value of a `Proxy-Authorization` header answering a Digest
+/// challenge.
+pub fn digest_authorization(
+    challenge: &DigestChallenge,
+    username: &str,
+    password: &str,
+    method: &str,
+    uri: &str,
+    cnonce: &str,
+) -> String {
+    let nc = "00000001";
+
+    let mut ha1 = md5_hex(&format!("{}:{}:{}", username, challenge.realm, password));
+    if challenge.is_sess() {
+        ha1 = md5_hex(&format!("{}:{}:{}", ha1, challenge.nonce, cnonce));
+    }
+    let ha2 = md5_hex(&format!("{}:{}", method, uri));
+
+    let response = if challenge.supports_qop_auth() {
+        md5_hex(&format!(
+            "{}:{}:{}:{}:auth:{}",
+            ha1, challenge.nonce, nc, cnonce, ha2
+        ))
+    } else {
+        md5_hex(&format!("{}:{}:{}", ha1, challenge.nonce, ha2))
+    };
+
+    let mut header = format!(
+        "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", response=\"{}\"",
+        escape(username),
+        escape(&challenge.realm),
+        escape(&challenge.nonce),
+        escape(uri),
+        response
+    );
+    if let Some(algorithm) = &challenge.algorithm {
+        header.push_str(&format!(", algorithm={}", algorithm));
+    }
+    if challenge.supports_qop_auth() {
+        header.push_str(&format!(", qop=auth, nc={}, cnonce=\"{}\"", nc, cnonce));
+    }
+    if let Some(opaque) = &challenge.opaque {
+        header.push_str(&format!(", opaque=\"{}\"", escape(opaque)));
+    }
+    header
+}
+
+/// Escapes `"` and `\` for a quoted string.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Generates a client nonce for Digest authentication.
+pub fn new_cnonce() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    md5_hex(&now.to_string())[..16].to_string()
+}
+
+fn md5_hex(s: &str) -> String {
+    format!("{:x}", md5::compute(s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn basic() {
+        assert_eq!(
+            basic_authorization("Aladdin", "open sesame"),
+            "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="
+        );
+    }
+
+    #[test]
+    fn basic_challenge() {
+        assert!(is_basic_challenge("Basic realm=\"proxy\""));
+        assert!(is_basic_challenge(" basic"));
+        assert!(!is_basic_challenge("Basically"));
+        assert!(!is_basic_challenge("Digest realm=\"r\", nonce=\"n\""));
+    }
+
+    #[test]
+    fn parse_challenge() {
+        let challenge = DigestChallenge::parse(
+            r#"Digest realm="testrealm@host.com", qop="auth,auth-int", nonce="dcd98b7102dd2f0e8b11d0f600bfb0c093", opaque="5ccc069c403ebaf9f0171e9517f40e41""#,
+        )
+        .unwrap();
+
+        assert_eq!(challenge.realm, "testrealm@host.com");
+        assert_eq!(challenge.nonce, "dcd98b7102dd2f0e8b11d0f600bfb0c093");
+        assert_eq!(
+            challenge.opaque.as_ref().unwrap(),
+            "5ccc069c403ebaf9f0171e9517f40e41"
+        );
+        assert!(challenge.supports_qop_auth());
+
+        assert_eq!(DigestChallenge::parse("Basic realm=\"proxy\""), None);
+        assert_eq!(
+            DigestChallenge::parse("Digest realm=\"r\", nonce=\"n\", algorithm=SHA-256"),
+            None
+        );
+    }
+
+    #[test]
+    fn digest_rfc2617_example() {
+        let challenge = DigestChallenge::parse(
+            r#"Digest realm="testrealm@host.com", qop="auth,auth-int", nonce="dcd98b7102dd2f0e8b11d0f600bfb0c093", opaque="5ccc069c403ebaf9f0171e9517f40e41""#,
+        )
+        .unwrap();
+
+        let header = digest_authorization(
+            &challenge,
+            "Mufasa",
+            "Circle Of Life",
+            "GET",
+            "/dir/index.html",
+            "0a4f113b",
+        );
+
+        assert!(header.contains("response=\"6629fae49393a05397450978507c4ef1\""));
+        assert!(header.contains("nc=00000001"));
+        assert!(header.contains("opaque=\"5ccc069c403ebaf9f0171e9517f40e41\""));
+    }
+
+    #[test]
+    fn quoted_values_are_escaped() {
+        let challenge = DigestChallenge::parse(r#"Digest realm="a \"b\"", nonce="n""#).unwrap();
+        assert_eq!(challenge.realm, r#"a "b""#);
+
+        let header = digest_authorization(&challenge, r"do\main", "pass", "CONNECT", "h:443", "c");
+        assert!(header.contains(r#"username="do\\main""#));
+        assert!(header.contains(r#"realm="a \"b\"""#));
+    }
+
+    #[test]
+    fn non_ascii_challenge_is_ignored() {
+        assert_eq!(DigestChallenge::parse("Digesté realm=\"r\""), None);
+        assert_eq!(DigestChallenge::parse("Dig€"), None);
+    }
+}
@@ -0,0 +1,41 @@
+use super::HttpStream;
+use crate::auth::Authentication;
+use crate::server::{pipe, shutdown_both};
+use crate::target_addr::ToTargetAddr;
+use futures::try_join;
+use std::io;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::net::ToSocketAddrs;
+
+pub async fn forward_tcp_to_http(
+    mut client: TcpStream,
+    proxy: impl ToSocketAddrs,
+    proxy_auth: &Authentication,
+    target: impl ToTargetAddr,
+) -> io::Result<()> {
+    println!("Accepted connection from {:?}", client.peer_addr().unwrap());
+
+    let (mut proxy_stream, buffered) = HttpStream::connect(proxy, target, proxy_auth)
+        .await?
+        .into_parts();
+
+    // bytes the proxy sent right after its response head
+    if !buffered.is_empty() {
+        client.write_all(&buffered).await?;
+    }
+
+    let (client_read, client_write) = client.split();
+    let (proxy_read, proxy_write) = proxy_stream.split();
+
+    try_join!(
+        pipe(client_read, proxy_write),
+        pipe(proxy_read, client_write),
+    )?;
+
+    println!("[client] joined task done");
+
+    shutdown_both(&client, &proxy_stream);
+
+    Ok(())
+}
@@ -0,0 +1,253 @@
+mod auth;
+mod internal;
+mod response;
+pub use internal::forward_tcp_to_http;
+pub use response::StatusError;
+
+use self::auth::{
+    basic_authorization, digest_authorization, is_basic_challenge, new_cnonce, DigestChallenge,
+};
+use self::response::{read_response, status_to_error, ResponseHead};
+use super::auth::Authentication;
+use super::happy_eyeballs;
+use super::target_addr::{TargetAddr, ToTargetAddr};
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::net::ToSocketAddrs;
+
+/// A tunnel to a target server established with an HTTP/1.1 CONNECT request.
+pub struct HttpStream {
+    socket: TcpStream,
+    buffered: Vec<u8>,
+}
+
+impl HttpStream {
+    /// Connects to a target server through an HTTP proxy.
+    ///
+    /// The request is first sent without credentials. With
+    /// `Authentication::Password`, a 407 answer is retried on a new
+    /// connection with credentials for the scheme the proxy challenges with,
+    /// Digest if it offers both. The password is only sent in clear text to a
+    /// proxy asking for Basic.
+    pub async fn connect<T, U>(proxy: T, target: U, auth: &Authentication) -> io::Result<HttpStream>
+    where
+        T: ToSocketAddrs,
+        U: ToTargetAddr,
+    {
+        let target = target.to_target_addr()?;
+        let authority = authority(&target);
+
+        let (socket, head, buffered) = Self::send_connect(&proxy, &authority, None).await?;
+
+        if head.status == 407 {
+            if let Authentication::Password { username, password } = auth {
+                if let Some(authorization) = answer_challenge(&head, username, password, &authority)
+                {
+                    drop(socket);
+                    let (socket, head, buffered) =
+                        Self::send_connect(&proxy, &authority, Some(&authorization)).await?;
+                    return Self::finish(socket, &head, buffered);
+                }
+            }
+        }
+
+        Self::finish(socket, &head, buffered)
+    }
+
+    /// Connects to a target server through an HTTP proxy using given
+    /// username and password.
+    pub async fn connect_with_password<T, U>(
+        proxy: T,
+        target: U,
+        username: &str,
+        password: &str,
+    ) -> io::Result<HttpStream>
+    where
+        T: ToSocketAddrs,
+        U: ToTargetAddr,
+    {
+        let auth = Authentication::Password {
+            username: username.to_string(),
+            password: password.to_string(),
+        };
+        Self::connect(proxy, target, &auth).await
+    }
+
+    async fn send_connect<T: ToSocketAddrs>(
+        proxy: &T,
+        authority: &str,
+        authorization: Option<&String>,
+    ) -> io::Result<(TcpStream, ResponseHead, Vec<u8>)> {
+        let mut socket = happy_eyeballs::connect(proxy).await?;
+
+        let mut request = format!(
+            "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\nProxy-Connection: Keep-Alive\r\n",
+            authority
+        );
+        if let Some(authorization) = authorization {
+            request.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+        }
+        request.push_str("\r\n");
+        socket.write_all(request.as_bytes()).await?;
+
+        let (head, buffered) = read_response(&mut socket).await?;
+        Ok((socket, head, buffered))
+    }
+
+    fn finish(socket: TcpStream, head: &ResponseHead, buffered: Vec<u8>) -> io::Result<HttpStream> {
+        if head.status < 200 || head.status > 299 {
+            return Err(status_to_error(head));
+        }
+
+        Ok(HttpStream { socket, buffered })
+    }
+
+    /// Returns the bytes the proxy sent after its response head.
+    ///
+    /// These were already read from the socket and belong to the tunnelled
+    /// stream, so they must be handled before reading from the inner
+    /// `TcpStream`.
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffered
+    }
+
+    /// Returns a shared reference to the inner `TcpStream`.
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    /// Returns a mutable reference to the inner `TcpStream`.
+    pub fn get_mut(&mut self) -> &mut TcpStream {
+        &mut self.socket
+    }
+
+    /// Consumes the `HttpStream`, returning the inner `TcpStream` and the
+    /// bytes already read past the response head.
+    pub fn into_parts(self) -> (TcpStream, Vec<u8>) {
+        (self.socket, self.buffered)
+    }
+}
+
+/// Builds the `Proxy-Authorization` value answering the challenges of a 407
+/// response, preferring Digest over Basic.
+///
+/// Returns `None` if neither is offered.
+fn answer_challenge(
+    head: &ResponseHead,
+    username: &str,
+    password: &str,
+    authority: &str,
+) -> Option<String> {
+    let digest = head
+        .header_values("proxy-authenticate")
+        .filter_map(DigestChallenge::parse)
+        .next();
+    if let Some(challenge) = digest {
+        return Some(digest_authorization(
+            &challenge,
+            username,
+            password,
+            "CONNECT",
+            authority,
+            &new_cnonce(),
+        ));
+    }
+
+    if head
+        .header_values("proxy-authenticate")
+        .any(is_basic_challenge)
+    {
+        return Some(basic_authorization(username, password));
+    }
+    None
+}
+
+/// Formats a target as the authority part of a CONNECT request.
+fn authority(target: &TargetAddr) -> String {
+    match target {
+        TargetAddr::Ip(SocketAddr::V4(addr)) => addr.to_string(),
+        TargetAddr::Ip(SocketAddr::V6(addr)) => format!("[{}]:{}", addr.ip(), addr.port()),
+        TargetAddr::Domain(domain, port) => format!("{}:{}", domain, port),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv6Addr, SocketAddrV6};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    /// Runs a proxy that answers the first CONNECT with `challenge` and the
+    /// second with 200, returning both request heads.
+    async fn challenged(challenge: &'static str) -> (String, String) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut heads = vec![];
+            for response in &[challenge, "HTTP/1.1 200 Connection established\r\n\r\n"] {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut head = vec![];
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.push(socket.read_u8().await.unwrap());
+                }
+                heads.push(String::from_utf8(head).unwrap());
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+            (heads.remove(0), heads.remove(0))
+        });
+
+        let auth = Authentication::Password {
+            username: "user".to_string(),
+            password: "secret".to_string(),
+        };
+        HttpStream::connect(proxy, ("example.com", 443), &auth)
+            .await
+            .unwrap();
+        server.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn digest_proxy_gets_no_basic_credentials() {
+        let (first, second) = challenged(
+            "HTTP/1.1 407 Proxy Authentication Required\r\n\
+             Proxy-Authenticate: Digest realm=\"r\", nonce=\"n\"\r\n\
+             Content-Length: 0\r\n\r\n",
+        )
+        .await;
+        assert!(!first.contains("Proxy-Authorization"));
+        assert!(second.contains("Proxy-Authorization: Digest username=\"user\""));
+        assert!(!second.contains("Basic"));
+    }
+
+    #[tokio::test]
+    async fn basic_challenge_is_answered() {
+        let (first, second) = challenged(
+            "HTTP/1.1 407 Proxy Authentication Required\r\n\
+             Proxy-Authenticate: Basic realm=\"r\"\r\n\
+             Content-Length: 0\r\n\r\n",
+        )
+        .await;
+        assert!(!first.contains("Proxy-Authorization"));
+        assert!(second.contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"));
+    }
+
+    #[test]
+    fn authority_format() {
+        assert_eq!(
+            authority(&TargetAddr::Domain("example.com".to_string(), 443)),
+            "example.com:443"
+        );
+        assert_eq!(
+            authority(&TargetAddr::Ip(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::LOCALHOST,
+                8080,
+                0,
+                0
+            )))),
+            "[::1]:8080"
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const MAX_HEAD_LEN: usize = 8192;
+
+/// The status line and headers of a response to a CONNECT request.
+#[derive(Debug, PartialEq)]
+pub struct ResponseHead {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl ResponseHead {
+    /// Returns the values of all headers with the given name (case-insensitive).
+    pub fn header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Reads the response head from the proxy.
+///
+/// Returns the parsed head together with any bytes received after the blank
+/// line that ends the head. Those bytes already belong to the tunnel.
+pub async fn read_response<R: AsyncRead + Unpin>(
+    socket: &mut R,
+) -> io::Result<(ResponseHead, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0; 1024];
+    loop {
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "proxy closed connection before sending a response",
+            ));
+        }
+
+        // the terminator may span two reads
+        let search_from = buf.len().saturating_sub(3);
+        buf.extend_from_slice(&chunk[..n]);
+
+        if let Some(pos) = find_head_end(&buf[search_from..]) {
+            let head_end = search_from + pos;
+            let head = parse_head(&buf[..head_end])?;
+            let rest = buf.split_off(head_end + 4);
+            return Ok((head, rest));
+        }
+
+        if buf.len() > MAX_HEAD_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "response head too long",
+            ));
+        }
+    }
+}
+
+fn find_head_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n")
+}
+
+fn parse_head(head: &[u8]) -> io::Result<ResponseHead> {
+    let head =
+        std::str::from_utf8(head).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut lines = head.split("\r\n");
+
+    let status_line = lines.next().unwrap_or("");
+    let mut parts = status_line.splitn(3, ' ');
+    match parts.next() {
+        Some(version) if version.starts_with("HTTP/1.") => {}
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid response version",
+            ))
+        }
+    }
+    let status = match parts.next().and_then(|s| s.parse().ok()) {
+        Some(status) => status,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid response status",
+            ))
+        }
+    };
+    let reason = parts.next().unwrap_or("").to_string();
+
+    let mut headers = vec![];
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let value = match parts.next() {
+            Some(v) => v.trim(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid response header",
+                ))
+            }
+        };
+        headers.push((name.to_string(), value.to_string()));
+    }
+
+    Ok(ResponseHead {
+        status,
+        reason,
+        headers,
+    })
+}
+
+/// A CONNECT request the proxy answered with a non-2xx status.
+///
+/// It is the inner error of the `io::Error` that `HttpStream::connect`
+/// returns, so it can be found with `io::Error::get_ref` and `downcast_ref`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusError {
+    pub status: u16,
+    pub reason: String,
+}
+
+impl StatusError {
+    /// Whether the status says the proxy could not reach the target: 502,
+    /// 503 or 504.
+    pub fn is_target_unreachable(&self) -> bool {
+        matches!(self.status, 502..=504)
+    }
+
+    /// Returns the `io::ErrorKind` this error converts to and what the status
+    /// means.
+    fn describe(&self) -> (io::ErrorKind, &'static str) {
+        match self.status {
+            400 => (io::ErrorKind::InvalidInput, "bad request"),
+            403 => (
+                io::ErrorKind::PermissionDenied,
+                "connection not allowed by proxy",
+            ),
+            404 => (io::ErrorKind::NotFound, "target not found"),
+            405 => (io::ErrorKind::Other, "CONNECT method not allowed"),
+            407 => (
+                io::ErrorKind::PermissionDenied,
+                "proxy authentication required",
+            ),
+            408 | 504 => (io::ErrorKind::TimedOut, "proxy timed out reaching target"),
+            502 => (io::ErrorKind::ConnectionRefused, "bad gateway"),
+            503 => (io::ErrorKind::ConnectionRefused, "service unavailable"),
+            _ => (io::ErrorKind::Other, "unexpected proxy response"),
+        }
+    }
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({} {})", self.describe().1, self.status, self.reason)
+    }
+}
+
+impl Error for StatusError {}
+
+/// Converts a non-2xx status into an error describing why the tunnel was not
+/// established.
+pub fn status_to_error(head: &ResponseHead) -> io::Error {
+    let error = StatusError {
+        status: head.status,
+        reason: head.reason.clone(),
+    };
+    io::Error::new(error.describe().0, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn read_response_keeps_trailing_bytes() {
+        let mut input: &[u8] = b"HTTP/1.1 200 Connection established\r\nVia: proxy\r\n\r\nSSH-2.0";
+        let (head, rest) = read_response(&mut input).await.unwrap();
+
+        assert_eq!(head.status, 200);
+        assert_eq!(head.reason, "Connection established");
+        assert_eq!(head.header_values("via").collect::<Vec<_>>(), vec!["proxy"]);
+        assert_eq!(rest, b"SSH-2.0");
+    }
+
+    #[tokio::test]
+    async fn read_response_rejects_non_http() {
+        let mut input: &[u8] = b"\x05\x00\r\n\r\n";
+        assert!(read_response(&mut input).await.is_err());
+    }
+
+    #[test]
+    fn status_errors() {
+        let head = |status| ResponseHead {
+            status,
+            reason: String::new(),
+            headers: vec![],
+        };
+
+        assert_eq!(
+            status_to_error(&head(407)).kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            status_to_error(&head(502)).kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+        assert_eq!(status_to_error(&head(504)).kind(), io::ErrorKind::TimedOut);
+
+        let error = status_to_error(&head(503));
+        let status = error.get_ref().unwrap().downcast_ref::<StatusError>();
+        assert!(status.unwrap().is_target_unreachable());
+        assert_eq!(error.to_string(), "service unavailable (503 )");
+    }
+}
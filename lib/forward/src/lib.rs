@@ -1,7 +1,13 @@
 pub mod auth;
+pub mod happy_eyeballs;
+pub mod http;
+pub mod resolver;
 pub mod server;
+pub mod socks4;
 pub mod socks5;
 pub mod target_addr;
+#[cfg(any(feature = "tls", test))]
+pub mod tls;
 
 pub use tokio;
 
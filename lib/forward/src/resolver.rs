@@ -0,0 +1,272 @@
+//! Hostname resolution for proxy addresses, with a cache in front of the
+//! system resolver by default.
+
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::lookup_host;
+
+/// Something that resolves hostnames to IP addresses.
+pub trait Resolve: Send + Sync {
+    /// Returns the addresses of `host` in order of preference.
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>>;
+}
+
+/// Resolves with the system resolver, one lookup per call.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolve for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        Box::pin(async move {
+            let addrs = lookup_host((host, 0)).await?;
+            Ok(addrs.map(|addr| addr.ip()).collect())
+        })
+    }
+}
+
+/// Resolves from a fixed map of hostnames, like a hosts file.
+#[derive(Debug, Clone, Default)]
+pub struct StaticResolver {
+    hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl StaticResolver {
+    pub fn new() -> StaticResolver {
+        StaticResolver::default()
+    }
+
+    /// Maps `host`, ignoring case, to `addrs`.
+    pub fn insert(&mut self, host: &str, addrs: Vec<IpAddr>) {
+        self.hosts.insert(host.to_ascii_lowercase(), addrs);
+    }
+}
+
+impl Resolve for StaticResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        let result = match self.hosts.get(&host.to_ascii_lowercase()) {
+            Some(addrs) => Ok(addrs.clone()),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("unknown host {}", host),
+            )),
+        };
+        Box::pin(async move { result })
+    }
+}
+
+/// How long `CachingResolver` keeps answers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheConfig {
+    /// Time a successful answer is reused.
+    pub ttl: Duration,
+
+    /// Time a failed lookup is answered with the same error without asking
+    /// again.
+    pub negative_ttl: Duration,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            ttl: Duration::from_secs(60),
+            negative_ttl: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    expires: Instant,
+    result: Result<Vec<IpAddr>, (io::ErrorKind, String)>,
+}
+
+/// Caches the answers of another resolver, failures included.
+///
+/// The system resolver does not tell record TTLs, so every answer is kept
+/// for the configured time.
+#[derive(Debug)]
+pub struct CachingResolver<R> {
+    inner: R,
+    config: CacheConfig,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl<R: Resolve> CachingResolver<R> {
+    pub fn new(inner: R, config: CacheConfig) -> CachingResolver<R> {
+        CachingResolver {
+            inner,
+            config,
+            entries: Mutex::default(),
+        }
+    }
+
+    fn cached(&self, host: &str) -> Option<io::Result<Vec<IpAddr>>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(host)?;
+        if entry.expires <= Instant::now() {
+            entries.remove(host);
+            return None;
+        }
+        Some(match &entry.result {
+            Ok(addrs) => Ok(addrs.clone()),
+            Err((kind, msg)) => Err(io::Error::new(*kind, msg.as_str())),
+        })
+    }
+}
+
+impl<R: Resolve> Resolve for CachingResolver<R> {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        Box::pin(async move {
+            let host = host.to_ascii_lowercase();
+            if let Some(result) = self.cached(&host) {
+                return result;
+            }
+
+            let result = self.inner.resolve(&host).await;
+            let (ttl, cached) = match &result {
+                Ok(addrs) => (self.config.ttl, Ok(addrs.clone())),
+                Err(error) => (
+                    self.config.negative_ttl,
+                    Err((error.kind(), error.to_string())),
+                ),
+            };
+            self.entries.lock().unwrap().insert(
+                host,
+                CacheEntry {
+                    expires: Instant::now() + ttl,
+                    result: cached,
+                },
+            );
+            result
+        })
+    }
+}
+
+/// A shared handle to a `Resolve` implementation.
+///
+/// Two handles are equal if they share the same resolver. The default one
+/// caches the answers of the system resolver.
+#[derive(Clone)]
+pub struct Resolver(Arc<dyn Resolve>);
+
+impl Resolver {
+    pub fn new<R: Resolve + 'static>(resolve: R) -> Resolver {
+        Resolver(Arc::new(resolve))
+    }
+
+    /// Returns the socket addresses of `host` with `port`, failing if it has
+    /// none.
+    pub async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let addrs = self.0.resolve(host).await?;
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no addresses for {}", host),
+            ));
+        }
+        Ok(addrs
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Resolver::new(CachingResolver::new(SystemResolver, CacheConfig::default()))
+    }
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Resolver")
+    }
+}
+
+impl PartialEq for Resolver {
+    fn eq(&self, other: &Resolver) -> bool {
+        let this = &*self.0 as *const dyn Resolve as *const u8;
+        let other = &*other.0 as *const dyn Resolve as *const u8;
+        this == other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts the lookups reaching a static map.
+    struct Counting {
+        hosts: StaticResolver,
+        lookups: AtomicUsize,
+    }
+
+    impl Resolve for Counting {
+        fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            self.hosts.resolve(host)
+        }
+    }
+
+    fn counting() -> Counting {
+        let mut hosts = StaticResolver::new();
+        hosts.insert("proxy.local", vec![IpAddr::from([10, 0, 0, 1])]);
+        Counting {
+            hosts,
+            lookups: AtomicUsize::new(0),
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_are_cached() {
+        let cache = CachingResolver::new(
+            counting(),
+            CacheConfig {
+                ttl: Duration::from_secs(60),
+                negative_ttl: Duration::from_secs(60),
+            },
+        );
+        for _ in 0..2 {
+            assert_eq!(
+                cache.resolve("Proxy.Local").await.unwrap(),
+                vec![IpAddr::from([10, 0, 0, 1])]
+            );
+            let error = cache.resolve("missing.local").await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        }
+        assert_eq!(cache.inner.lookups.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn expired_answers_are_refreshed() {
+        let cache = CachingResolver::new(
+            counting(),
+            CacheConfig {
+                ttl: Duration::from_secs(0),
+                negative_ttl: Duration::from_secs(0),
+            },
+        );
+        cache.resolve("proxy.local").await.unwrap();
+        cache.resolve("proxy.local").await.unwrap();
+        assert_eq!(cache.inner.lookups.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn lookup_adds_port() {
+        let mut hosts = StaticResolver::new();
+        hosts.insert("proxy.local", vec![IpAddr::from([10, 0, 0, 1])]);
+        let resolver = Resolver::new(hosts);
+        assert_eq!(
+            resolver.lookup("proxy.local", 1080).await.unwrap(),
+            vec![SocketAddr::from(([10, 0, 0, 1], 1080))]
+        );
+        assert_eq!(resolver, resolver.clone());
+        assert_ne!(resolver, Resolver::default());
+    }
+}
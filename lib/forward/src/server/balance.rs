@@ -0,0 +1,212 @@
+use super::stats::ServerStats;
+use crate::target_addr::TargetAddr;
+use rand::Rng;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Virtual nodes per target on the consistent hash ring.
+const VIRTUAL_NODES: usize = 64;
+
+/// How connections are spread over the backend targets.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BalanceStrategy {
+    #[default]
+    RoundRobin,
+    Random,
+
+    /// The target with the fewest active connections, the first one on ties.
+    LeastConnections,
+
+    /// Hash the client IP, so a client keeps reaching the same target as long
+    /// as the set of targets does not change.
+    ConsistentHash,
+}
+
+impl FromStr for BalanceStrategy {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<BalanceStrategy> {
+        match s.to_ascii_lowercase().as_str() {
+            "round-robin" => Ok(BalanceStrategy::RoundRobin),
+            "random" => Ok(BalanceStrategy::Random),
+            "least-connections" => Ok(BalanceStrategy::LeastConnections),
+            "consistent-hash" => Ok(BalanceStrategy::ConsistentHash),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unknown balance strategy",
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Balancer {
+    strategy: BalanceStrategy,
+    len: usize,
+    next: AtomicUsize,
+
+    /// Sorted `(hash, target index)` pairs, only used by `ConsistentHash`.
+    ring: Vec<(u64, usize)>,
+}
+
+impl Balancer {
+    pub fn new(strategy: BalanceStrategy, targets: &[TargetAddr]) -> Balancer {
+        let mut ring = vec![];
+        if strategy == BalanceStrategy::ConsistentHash {
+            for (index, target) in targets.iter().enumerate() {
+                for node in 0..VIRTUAL_NODES {
+                    ring.push((hash(&(format!("{:?}", target), node)), index));
+                }
+            }
+            ring.sort();
+        }
+
+        Balancer {
+            strategy,
+            len: targets.len(),
+            next: AtomicUsize::new(0),
+            ring,
+        }
+    }
+
+    /// Picks the index of the target for a new connection from `client`.
+    ///
+    /// Targets whose circuit breaker rejects clients are skipped, unless all
+    /// of them do.
+    pub fn pick(&self, client: IpAddr, stats: &ServerStats) -> usize {
+        if self.len <= 1 {
+            return 0;
+        }
+
+        let mut available: Vec<usize> = (0..self.len).filter(|i| !stats.is_rejecting(*i)).collect();
+        if available.is_empty() {
+            available = (0..self.len).collect();
+        }
+
+        match self.strategy {
+            BalanceStrategy::RoundRobin => {
+                available[self.next.fetch_add(1, Ordering::Relaxed) % available.len()]
+            }
+            BalanceStrategy::Random => available[rand::thread_rng().gen_range(0, available.len())],
+            BalanceStrategy::LeastConnections => available
+                .iter()
+                .copied()
+                .min_by_key(|i| stats.active_connections(*i))
+                .unwrap_or(0),
+            BalanceStrategy::ConsistentHash => {
+                let h = hash(&client);
+                let pos = match self.ring.binary_search(&(h, 0)) {
+                    Ok(pos) | Err(pos) => pos,
+                };
+                // The next target on the ring that is available.
+                (0..self.ring.len())
+                    .map(|i| self.ring[(pos + i) % self.ring.len()].1)
+                    .find(|i| available.contains(i))
+                    .unwrap_or(self.ring[pos % self.ring.len()].1)
+            }
+        }
+    }
+}
+
+fn hash<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::pool::UpstreamPool;
+    use crate::server::CircuitBreakerConfig;
+    use crate::socks5::Socks5Error;
+    use crate::target_addr::ToTargetAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::broadcast;
+
+    fn stats(targets: &[TargetAddr], breaker: Option<&CircuitBreakerConfig>) -> ServerStats {
+        let (events, _) = broadcast::channel(1);
+        let pool = UpstreamPool::new(
+            vec![],
+            Default::default(),
+            Default::default(),
+            None,
+            Default::default(),
+            events.clone(),
+        );
+        ServerStats::new(targets, Arc::new(pool), breaker, &events)
+    }
+
+    fn targets(n: u16) -> Vec<TargetAddr> {
+        (0..n)
+            .map(|i| ("127.0.0.1", 8000 + i).to_target_addr().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn round_robin() {
+        let targets = targets(3);
+        let stats = stats(&targets, None);
+        let balancer = Balancer::new(BalanceStrategy::RoundRobin, &targets);
+        let client = "10.0.0.1".parse().unwrap();
+
+        let picked: Vec<usize> = (0..4).map(|_| balancer.pick(client, &stats)).collect();
+        assert_eq!(picked, vec![0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn least_connections() {
+        let targets = targets(3);
+        let stats = stats(&targets, None);
+        let balancer = Balancer::new(BalanceStrategy::LeastConnections, &targets);
+        let client = "10.0.0.1".parse().unwrap();
+
+        let _a = stats.connection_started(0);
+        let _b = stats.connection_started(1);
+        assert_eq!(balancer.pick(client, &stats), 2);
+
+        drop(_a);
+        assert_eq!(balancer.pick(client, &stats), 0);
+    }
+
+    #[test]
+    fn consistent_hash_is_sticky() {
+        let targets = targets(4);
+        let stats = stats(&targets, None);
+        let balancer = Balancer::new(BalanceStrategy::ConsistentHash, &targets);
+
+        for i in 0..32 {
+            let client = IpAddr::from([10, 0, 0, i]);
+            let first = balancer.pick(client, &stats);
+            assert!(first < 4);
+            assert_eq!(balancer.pick(client, &stats), first);
+        }
+    }
+
+    #[test]
+    fn rejecting_targets_are_skipped() {
+        let targets = targets(3);
+        let config = CircuitBreakerConfig {
+            failure_threshold: 1,
+            open_for: Duration::from_secs(60),
+        };
+        let stats = stats(&targets, Some(&config));
+        assert!(stats.allow_connection(0));
+        stats.connection_failed(0, &Socks5Error::Reply(5).into());
+
+        let balancer = Balancer::new(BalanceStrategy::RoundRobin, &targets);
+        let client = "10.0.0.1".parse().unwrap();
+        let picked: Vec<usize> = (0..4).map(|_| balancer.pick(client, &stats)).collect();
+        assert_eq!(picked, vec![1, 2, 1, 2]);
+
+        let balancer = Balancer::new(BalanceStrategy::ConsistentHash, &targets);
+        for i in 0..32 {
+            assert_ne!(balancer.pick(IpAddr::from([10, 0, 0, i]), &stats), 0);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use super::events::ForwardServerEvent;
+use crate::target_addr::TargetAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// When to stop sending clients to a target that keeps failing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive connections the target failed, such as a CONNECT the
+    /// proxy could not complete, after which the breaker opens. Proxy
+    /// failures do not count.
+    pub failure_threshold: u32,
+
+    /// How long new clients are rejected before a trial connection is let
+    /// through.
+    pub open_for: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failure_threshold: 5,
+            open_for: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The state of the circuit breaker of a target.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BreakerState {
+    /// Clients are forwarded to the target.
+    #[default]
+    Closed,
+
+    /// The target failed too often, new clients are rejected without
+    /// contacting any proxy.
+    Open,
+
+    /// The open period is over. One trial connection is let through, its
+    /// result closes or reopens the breaker.
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct BreakerInner {
+    state: BreakerState,
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    trial_running: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    target: TargetAddr,
+    config: CircuitBreakerConfig,
+    inner: Mutex<BreakerInner>,
+    events: broadcast::Sender<ForwardServerEvent>,
+}
+
+impl CircuitBreaker {
+    pub fn new(
+        target: TargetAddr,
+        config: CircuitBreakerConfig,
+        events: broadcast::Sender<ForwardServerEvent>,
+    ) -> CircuitBreaker {
+        CircuitBreaker {
+            target,
+            config,
+            inner: Mutex::new(BreakerInner {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                open_until: None,
+                trial_running: false,
+            }),
+            events,
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// Returns whether `allow` would refuse a client now, without changing
+    /// the state.
+    pub fn is_rejecting(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::Closed => false,
+            BreakerState::Open => match inner.open_until {
+                Some(until) => until > Instant::now(),
+                None => false,
+            },
+            BreakerState::HalfOpen => inner.trial_running,
+        }
+    }
+
+    /// Returns whether a new client may be forwarded to the target.
+    ///
+    /// A `true` must be followed by `record_success`, `record_failure` or
+    /// `record_inconclusive`.
+    pub fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::Open => match inner.open_until {
+                Some(until) if until > Instant::now() => false,
+                _ => {
+                    inner.trial_running = true;
+                    self.set_state(&mut inner, BreakerState::HalfOpen);
+                    true
+                }
+            },
+            BreakerState::HalfOpen if inner.trial_running => false,
+            BreakerState::HalfOpen => {
+                inner.trial_running = true;
+                true
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = 0;
+        inner.trial_running = false;
+        if inner.state != BreakerState::Closed {
+            inner.open_until = None;
+            self.set_state(&mut inner, BreakerState::Closed);
+        }
+    }
+
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures += 1;
+        inner.trial_running = false;
+        let open = match inner.state {
+            BreakerState::Closed => inner.consecutive_failures >= self.config.failure_threshold,
+            BreakerState::HalfOpen => true,
+            BreakerState::Open => false,
+        };
+        if open {
+            inner.open_until = Some(Instant::now() + self.config.open_for);
+            self.set_state(&mut inner, BreakerState::Open);
+        }
+    }
+
+    /// Records a failed connection the target is not to blame for, such as
+    /// a proxy handshake failure. It only ends a running trial.
+    pub fn record_inconclusive(&self) {
+        self.inner.lock().unwrap().trial_running = false;
+    }
+
+    fn set_state(&self, inner: &mut BreakerInner, state: BreakerState) {
+        inner.state = state;
+        println!("Circuit breaker of {:?} is {:?}", self.target, state);
+        // Nobody listening is fine.
+        let _ = self.events.send(ForwardServerEvent::CircuitBreaker {
+            target: self.target.clone(),
+            state,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target_addr::ToTargetAddr;
+
+    fn breaker(open_for: Duration) -> (CircuitBreaker, broadcast::Receiver<ForwardServerEvent>) {
+        let (tx, rx) = broadcast::channel(16);
+        let target = ("127.0.0.1", 80).to_target_addr().unwrap();
+        let config = CircuitBreakerConfig {
+            failure_threshold: 2,
+            open_for,
+        };
+        (CircuitBreaker::new(target, config, tx), rx)
+    }
+
+    #[test]
+    fn opens_after_threshold() {
+        let (breaker, mut events) = breaker(Duration::from_secs(60));
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(breaker.is_rejecting());
+        assert!(!breaker.allow());
+
+        assert_eq!(
+            events.try_recv().unwrap(),
+            ForwardServerEvent::CircuitBreaker {
+                target: breaker.target.clone(),
+                state: BreakerState::Open,
+            }
+        );
+    }
+
+    #[test]
+    fn half_open_trial() {
+        let (breaker, _events) = breaker(Duration::from_secs(0));
+        breaker.record_failure();
+        breaker.record_failure();
+
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(!breaker.allow());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        assert!(breaker.allow());
+        breaker.record_success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn inconclusive_trial_lets_another_one_through() {
+        let (breaker, _events) = breaker(Duration::from_secs(0));
+        breaker.record_failure();
+        breaker.record_failure();
+
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+        breaker.record_inconclusive();
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(breaker.allow());
+    }
+}
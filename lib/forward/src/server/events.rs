@@ -0,0 +1,31 @@
+use super::breaker::BreakerState;
+use super::health::Health;
+use crate::target_addr::TargetAddr;
+
+/// Buffered events per subscriber; slow subscribers miss the oldest ones.
+pub(crate) const EVENT_CAPACITY: usize = 64;
+
+/// Something noteworthy that happened in a running `ForwardServer`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ForwardServerEvent {
+    /// The circuit breaker of a target changed state.
+    CircuitBreaker {
+        target: TargetAddr,
+        state: BreakerState,
+    },
+
+    /// A health check changed the health of an upstream proxy. `error` is
+    /// the error of the check if it failed.
+    UpstreamHealth {
+        proxy: TargetAddr,
+        health: Health,
+        error: Option<String>,
+    },
+
+    /// In reverse mode, the proxy listens on `addr` for the next connection.
+    ReverseBound { addr: TargetAddr },
+
+    /// A SOCKS5 proxy accepted a connection without the credentials it was
+    /// offered.
+    AuthDowngraded { proxy: TargetAddr },
+}
@@ -0,0 +1,71 @@
+use super::pool::UpstreamPool;
+use super::stopping;
+use crate::target_addr::TargetAddr;
+use futures::future::FutureExt;
+use futures::select;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::time::{delay_for, timeout};
+
+/// Periodic probes of the upstream proxies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthCheckConfig {
+    /// Time between two probes of the same proxy.
+    pub interval: Duration,
+
+    /// How long a probe may take before the proxy counts as unhealthy.
+    pub timeout: Duration,
+
+    /// The target each probe asks the proxy to connect to.
+    pub target: TargetAddr,
+}
+
+/// The result of the latest health check of an upstream proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Health {
+    /// Not probed yet, or health checks are disabled.
+    #[default]
+    Unknown,
+    Healthy,
+
+    /// The latest probe failed. The proxy is not used while another one is
+    /// healthy.
+    Unhealthy,
+}
+
+/// Probes the upstream at `index` until the server stops.
+///
+/// A probe runs the full handshake: TCP connect, method negotiation,
+/// authentication and a CONNECT to the check target.
+pub(crate) async fn run_health_checks(
+    pool: Arc<UpstreamPool>,
+    index: usize,
+    config: HealthCheckConfig,
+    mut state_rx: watch::Receiver<u8>,
+) {
+    loop {
+        let started = Instant::now();
+        let result = match timeout(
+            config.timeout,
+            pool.upstream(index)
+                .connect(pool.resolver(), &config.target, None),
+        )
+        .await
+        {
+            Ok(Ok(_)) => Ok(started.elapsed()),
+            Ok(Err(error)) => Err(error),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "health check timed out",
+            )),
+        };
+        pool.report_health(index, result);
+
+        select! {
+            _ = delay_for(config.interval).fuse() => {},
+            _ = stopping(&mut state_rx).fuse() => break,
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use crate::auth::Authentication;
+use rand::Rng;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The longest username SOCKS5 can carry (RFC 1929).
+const MAX_USERNAME_LEN: usize = 255;
+
+/// Which connections share SOCKS credentials, for proxies such as Tor that
+/// pick the exit or circuit by credentials.
+///
+/// SOCKS proxies get a generated username: the configured one with a
+/// suffix, or a new one with a random password if none is configured.
+/// Configured passwords are kept. HTTP proxies keep their own credentials.
+///
+/// Only TCP forwarding supports isolation.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Isolation {
+    /// Every connection uses the proxy's configured credentials.
+    #[default]
+    None,
+
+    /// Every connection gets its own credentials.
+    PerConnection,
+
+    /// Connections from the same client IP share credentials.
+    PerClientIp,
+
+    /// Connections made within the same time window share credentials.
+    PerTimeWindow(Duration),
+}
+
+impl FromStr for Isolation {
+    type Err = io::Error;
+
+    /// Parses `none`, `per-connection`, `per-client` or `per-window:<seconds>`.
+    fn from_str(s: &str) -> io::Result<Isolation> {
+        const WINDOW: &str = "per-window:";
+        let s = s.to_ascii_lowercase();
+        if let Some(secs) = s.strip_prefix(WINDOW) {
+            return match secs.parse() {
+                Ok(secs) if secs > 0 => Ok(Isolation::PerTimeWindow(Duration::from_secs(secs))),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "invalid isolation window",
+                )),
+            };
+        }
+
+        match s.as_str() {
+            "none" => Ok(Isolation::None),
+            "per-connection" => Ok(Isolation::PerConnection),
+            "per-client" => Ok(Isolation::PerClientIp),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unknown isolation, expected none, per-connection, per-client or per-window:<seconds>",
+            )),
+        }
+    }
+}
+
+/// Generates the credentials of new connections.
+#[derive(Debug)]
+pub(crate) struct Isolator {
+    isolation: Isolation,
+
+    /// Random per server run, so credentials are not reused across restarts
+    /// and client IPs cannot be recovered from them.
+    secret: u64,
+
+    /// Random per server run as well, used for proxies without a configured
+    /// password.
+    password: String,
+    connections: AtomicU64,
+}
+
+impl Isolator {
+    pub fn new(isolation: Isolation) -> Isolator {
+        Isolator {
+            isolation,
+            secret: rand::thread_rng().gen(),
+            password: format!("{:016x}", rand::thread_rng().gen::<u64>()),
+            connections: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the credentials for a new connection from `client`, or `None`
+    /// to use the proxy's own. See `isolate` for how they are combined with
+    /// those of a proxy.
+    pub fn credentials(&self, client: IpAddr) -> Option<Authentication> {
+        let key = match self.isolation {
+            Isolation::None => return None,
+            Isolation::PerConnection => {
+                let n = self.connections.fetch_add(1, Ordering::Relaxed);
+                hash(&(self.secret, "connection", n))
+            }
+            Isolation::PerClientIp => hash(&(self.secret, "client", client)),
+            Isolation::PerTimeWindow(window) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                let n = now.as_secs() / window.as_secs().max(1);
+                hash(&(self.secret, "window", n))
+            }
+        };
+
+        Some(Authentication::Password {
+            username: format!("forward-{:016x}", key),
+            password: self.password.clone(),
+        })
+    }
+}
+
+/// Combines the `configured` credentials of a SOCKS proxy with the
+/// `isolation` ones: the configured username gets the isolation one as a
+/// suffix and the configured password is kept.
+///
+/// The configured username is cut short if needed for the result to fit in
+/// the 255 bytes SOCKS5 allows.
+pub(crate) fn isolate(configured: &Authentication, isolation: &Authentication) -> Authentication {
+    match (configured, isolation) {
+        (
+            Authentication::Password { username, password },
+            Authentication::Password {
+                username: suffix, ..
+            },
+        ) => {
+            let mut len = username.len().min(MAX_USERNAME_LEN - 1 - suffix.len());
+            while !username.is_char_boundary(len) {
+                len -= 1;
+            }
+            Authentication::Password {
+                username: format!("{}-{}", &username[..len], suffix),
+                password: password.clone(),
+            }
+        }
+        _ => isolation.clone(),
+    }
+}
+
+fn hash<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_isolation() {
+        assert_eq!(
+            "per-client".parse::<Isolation>().unwrap(),
+            Isolation::PerClientIp
+        );
+        assert_eq!(
+            "per-window:600".parse::<Isolation>().unwrap(),
+            Isolation::PerTimeWindow(Duration::from_secs(600))
+        );
+        assert!("per-window:0".parse::<Isolation>().is_err());
+        assert!("per-host".parse::<Isolation>().is_err());
+    }
+
+    #[test]
+    fn credentials_per_isolation() {
+        let a = IpAddr::from([10, 0, 0, 1]);
+        let b = IpAddr::from([10, 0, 0, 2]);
+
+        assert_eq!(Isolator::new(Isolation::None).credentials(a), None);
+
+        let per_client = Isolator::new(Isolation::PerClientIp);
+        assert_eq!(per_client.credentials(a), per_client.credentials(a));
+        assert_ne!(per_client.credentials(a), per_client.credentials(b));
+
+        let per_connection = Isolator::new(Isolation::PerConnection);
+        assert_ne!(per_connection.credentials(a), per_connection.credentials(a));
+
+        let per_window = Isolator::new(Isolation::PerTimeWindow(Duration::from_secs(3600)));
+        assert_eq!(per_window.credentials(a), per_window.credentials(b));
+    }
+
+    #[test]
+    fn configured_password_is_kept() {
+        let isolator = Isolator::new(Isolation::PerClientIp);
+        let generated = isolator.credentials(IpAddr::from([10, 0, 0, 1])).unwrap();
+        let configured = Authentication::Password {
+            username: "user".to_string(),
+            password: "secret".to_string(),
+        };
+
+        match isolate(&configured, &generated) {
+            Authentication::Password { username, password } => {
+                assert!(username.starts_with("user-forward-"));
+                assert_eq!(password, "secret");
+            }
+            Authentication::None => panic!("credentials expected"),
+        }
+        match generated {
+            Authentication::Password { password, .. } => {
+                assert_ne!(password, format!("{:016x}", isolator.secret))
+            }
+            Authentication::None => panic!("credentials expected"),
+        }
+    }
+
+    #[test]
+    fn long_username_is_cut_short() {
+        let isolator = Isolator::new(Isolation::PerConnection);
+        let generated = isolator.credentials(IpAddr::from([10, 0, 0, 1])).unwrap();
+        let suffix = match &generated {
+            Authentication::Password { username, .. } => format!("-{}", username),
+            Authentication::None => panic!("credentials expected"),
+        };
+        let configured = Authentication::Password {
+            username: "é".repeat(200),
+            password: "secret".to_string(),
+        };
+
+        match isolate(&configured, &generated) {
+            Authentication::Password { username, .. } => {
+                assert!(username.len() <= MAX_USERNAME_LEN);
+                assert!(username.len() >= MAX_USERNAME_LEN - 1);
+                assert!(username.starts_with("éé"));
+                assert!(username.ends_with(&suffix));
+            }
+            Authentication::None => panic!("credentials expected"),
+        }
+    }
+}
@@ -1,23 +1,179 @@
 use crate::auth::Authentication;
-use crate::socks5::forward_tcp_to_socks5;
+use crate::resolver::Resolver;
 use crate::target_addr::TargetAddr;
-use futures::future::{try_join_all, FutureExt};
+use futures::future::{try_join_all, Fuse, FutureExt};
 use futures::select;
+use std::future::Future;
 use std::io;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
-use tokio::sync::watch;
+use tokio::sync::{broadcast, watch};
 use tokio::task::JoinHandle;
+use tokio::time::delay_for;
+
+mod balance;
+pub use balance::BalanceStrategy;
+use balance::Balancer;
+
+mod breaker;
+pub use breaker::{BreakerState, CircuitBreakerConfig};
+
+mod events;
+pub use events::ForwardServerEvent;
+
+mod health;
+pub use health::{Health, HealthCheckConfig};
+
+mod isolation;
+pub use isolation::Isolation;
+use isolation::Isolator;
 
 mod pipe;
 pub use pipe::pipe;
+pub(crate) use pipe::shutdown_both;
+
+mod pool;
+use pool::UpstreamPool;
+pub use pool::{FailoverConfig, UpstreamSelection};
+
+mod retry;
+pub use retry::RetryPolicy;
+
+mod stats;
+pub use stats::{BackendStats, ServerStats, UpstreamStatus};
+
+mod reverse;
+
+mod udp;
+
+mod upstream;
+pub use upstream::{ProxyProtocol, Upstream};
+
+mod wait;
+pub use wait::WaitForUpstream;
+use wait::WaitQueue;
+
+mod warm;
+pub use warm::WarmPoolConfig;
+
+/// What the server listens for.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum ForwardMode {
+    /// Accept TCP connections and tunnel each one with a CONNECT.
+    #[default]
+    Tcp,
+
+    /// Receive UDP datagrams and relay them with a UDP ASSOCIATE per client
+    /// address. A client without traffic for `idle_timeout` loses its relay.
+    ///
+    /// All upstream proxies must speak SOCKS5.
+    Udp { idle_timeout: Duration },
+
+    /// Have the proxy listen with BIND and relay each connection it accepts
+    /// to the targets, which are local services here. `bind_addr` is unused.
+    ///
+    /// Most proxies only accept a connection coming from `peer`. All
+    /// upstream proxies must speak SOCKS5.
+    Reverse { peer: TargetAddr },
+}
 
 #[derive(Debug, PartialEq)]
 pub struct ForwardServerConfig {
     pub bind_addr: SocketAddr,
-    pub proxy: TargetAddr,
-    pub proxy_auth: Authentication,
-    pub target: TargetAddr,
+    pub mode: ForwardMode,
+
+    /// Upstream proxies, in order of preference with
+    /// `UpstreamSelection::Ordered`.
+    pub upstreams: Vec<Upstream>,
+    pub upstream_selection: UpstreamSelection,
+
+    /// Looks up the hostnames of upstream proxies.
+    pub resolver: Resolver,
+
+    /// Which connections share SOCKS credentials. Only TCP forwarding
+    /// supports it.
+    pub isolation: Isolation,
+    pub failover: FailoverConfig,
+
+    /// Retry the upstream leg of a connection on transient errors if set.
+    pub retry: Option<RetryPolicy>,
+
+    /// Hold clients while no upstream proxy can be reached if set, instead
+    /// of closing them at once.
+    pub wait_for_upstream: Option<WaitForUpstream>,
+
+    /// Reject clients locally while a target keeps failing if set.
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+
+    /// Keep authenticated sessions ready for TCP clients if set. Only SOCKS5
+    /// proxies get them, and not with isolation.
+    pub warm_pool: Option<WarmPoolConfig>,
+
+    /// Probe the upstream proxies periodically if set.
+    pub health_check: Option<HealthCheckConfig>,
+
+    /// Backend targets the connections are spread over.
+    pub targets: Vec<TargetAddr>,
+    pub balance: BalanceStrategy,
+}
+
+impl ForwardServerConfig {
+    /// Creates a config with default options.
+    pub fn new(
+        bind_addr: SocketAddr,
+        upstreams: Vec<Upstream>,
+        targets: Vec<TargetAddr>,
+    ) -> ForwardServerConfig {
+        ForwardServerConfig {
+            bind_addr,
+            mode: ForwardMode::default(),
+            upstreams,
+            upstream_selection: UpstreamSelection::default(),
+            resolver: Resolver::default(),
+            isolation: Isolation::default(),
+            failover: FailoverConfig::default(),
+            retry: None,
+            wait_for_upstream: None,
+            circuit_breaker: None,
+            warm_pool: None,
+            health_check: None,
+            targets,
+            balance: BalanceStrategy::default(),
+        }
+    }
+}
+
+/// Connects to `target` through the pool, retrying transient failures as the
+/// policy allows.
+async fn connect_with_retry(
+    pool: &UpstreamPool,
+    target: &TargetAddr,
+    isolation: Option<&Authentication>,
+    policy: Option<&RetryPolicy>,
+) -> io::Result<upstream::UpstreamStream> {
+    let mut retry = 0;
+    loop {
+        let error = match pool.connect(target, isolation).await {
+            Ok(stream) => return Ok(stream),
+            Err(error) => error,
+        };
+
+        let policy = match policy {
+            Some(policy) if retry + 1 < policy.max_attempts && policy.is_retryable(&error) => {
+                policy
+            }
+            _ => return Err(error),
+        };
+        let delay = policy.backoff(retry);
+        retry += 1;
+        eprintln!(
+            "Connect to {:?} failed: {}, retry {} in {:?}",
+            target, error, retry, delay
+        );
+        delay_for(delay).await;
+    }
 }
 
 #[derive(Debug)]
@@ -35,15 +191,42 @@ pub struct ForwardServer {
     state_tx: watch::Sender<u8>,
     state_rx: watch::Receiver<u8>,
     config: ForwardServerConfig,
+    pool: Arc<UpstreamPool>,
+    stats: ServerStats,
+    events: broadcast::Sender<ForwardServerEvent>,
 }
 
 const STATE_STARTED: u8 = 1;
 const STATE_STOPPING: u8 = 2;
 const STATE_STOPPED: u8 = 0;
 
+/// Resolves once the server leaves the started state.
+async fn stopping(state_rx: &mut watch::Receiver<u8>) {
+    while let Some(value) = state_rx.recv().await {
+        if value != STATE_STARTED {
+            break;
+        }
+    }
+}
+
 impl ForwardServer {
     pub fn new(config: ForwardServerConfig) -> ForwardServer {
         let (tx, rx) = watch::channel(0);
+        let (events, _) = broadcast::channel(events::EVENT_CAPACITY);
+        let pool = Arc::new(UpstreamPool::new(
+            config.upstreams.clone(),
+            config.failover.clone(),
+            config.upstream_selection,
+            config.warm_pool.clone(),
+            config.resolver.clone(),
+            events.clone(),
+        ));
+        let stats = ServerStats::new(
+            &config.targets,
+            pool.clone(),
+            config.circuit_breaker.as_ref(),
+            &events,
+        );
 
         ForwardServer {
             tasks: vec![],
@@ -51,6 +234,9 @@ impl ForwardServer {
             state: ForwardServerState::Stopped,
             state_tx: tx,
             state_rx: rx,
+            pool,
+            stats,
+            events,
         }
     }
 
@@ -59,42 +245,118 @@ impl ForwardServer {
         T: std::future::Future + Send + Unpin + 'static,
         T::Output: Send + 'static,
     {
+        if self.config.upstreams.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no upstream proxy configured",
+            ));
+        }
+        if self.config.targets.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no target configured",
+            ));
+        }
+        if self.config.isolation != Isolation::None && self.config.mode != ForwardMode::Tcp {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "isolation is only supported with TCP forwarding",
+            ));
+        }
+
         match self.state {
             ForwardServerState::Stopped => {
                 self.state = ForwardServerState::Started;
                 // TODO:
-                match self.state_tx.broadcast(STATE_STARTED) {
-                    Ok(_) => {}
-                    Err(_) => {}
-                };
+                let _ = self.state_tx.broadcast(STATE_STARTED);
             }
             // ForwardServerState::Stopping => {}
             _ => return Ok(()),
         }
 
+        if let Some(health_check) = &self.config.health_check {
+            for index in 0..self.pool.len() {
+                tokio::spawn(health::run_health_checks(
+                    self.pool.clone(),
+                    index,
+                    health_check.clone(),
+                    self.state_rx.clone(),
+                ));
+            }
+        }
+
+        if self.pool.warm().is_some()
+            && self.config.mode == ForwardMode::Tcp
+            && self.config.isolation == Isolation::None
+        {
+            for index in 0..self.pool.len() {
+                if self.pool.upstream(index).protocol == ProxyProtocol::Socks5 {
+                    tokio::spawn(warm::run_warm_pool(
+                        self.pool.clone(),
+                        index,
+                        self.state_rx.clone(),
+                    ));
+                }
+            }
+        }
+
+        let balancer = Balancer::new(self.config.balance, &self.config.targets);
+        let mut stopper = stopper.map(|s| s.fuse());
+        match self.config.mode.clone() {
+            ForwardMode::Tcp => self.serve_tcp(&mut stopper, &balancer).await?,
+            ForwardMode::Udp { idle_timeout } => {
+                self.serve_udp(&mut stopper, &balancer, idle_timeout)
+                    .await?
+            }
+            ForwardMode::Reverse { peer } => {
+                self.serve_reverse(&mut stopper, &balancer, peer).await?
+            }
+        }
+
+        // TODO: error handling
+        println!("SERVER try_join_all...");
+        let res = try_join_all(&mut self.tasks).await;
+        println!("SERVER try_join_all done!");
+
+        self.tasks = vec![];
+
+        res?;
+
+        self.state = ForwardServerState::Stopped;
+        // TODO:
+        let _ = self.state_tx.broadcast(STATE_STOPPED);
+
+        Ok(())
+    }
+
+    /// Accepts TCP clients and forwards each one until the stopper fires.
+    async fn serve_tcp<S>(
+        &mut self,
+        stopper: &mut Option<Fuse<S>>,
+        balancer: &Balancer,
+    ) -> io::Result<()>
+    where
+        S: Future + Unpin,
+    {
         // TODO .expect(&format!("Address {} is already in use", addr));
         let bind_addr = self.config.bind_addr;
 
         let mut listener = TcpListener::bind(bind_addr).await?;
         // println!("Server running on {}", bind_addr);
 
-        let mut stopper = match stopper {
-            Some(s) => Some(s.fuse()),
-            None => None,
-        };
+        let wait_queue = self.config.wait_for_upstream.clone().map(|config| {
+            let retry = self.config.retry.clone().unwrap_or_default();
+            Arc::new(WaitQueue::new(config, retry))
+        });
+        let isolator = Isolator::new(self.config.isolation);
+
         loop {
             let accepted = match stopper.as_mut() {
                 None => listener.accept().await,
                 Some(mut s) => select! {
                     accept = listener.accept().fuse() => accept,
                     recv = s => {
-                        self.state = ForwardServerState::Stopping;
-                        // TODO:
-                        match self.state_tx.broadcast(STATE_STOPPING) {
-                            Ok(_) => {}
-                            Err(_) => {}
-                        };
-
+                        self.stop_requested();
                         break;
                     },
                 },
@@ -104,45 +366,73 @@ impl ForwardServer {
             let (socket, socket_addr) = accepted?;
             println!("Accepted at {}", socket_addr);
 
-            let proxy = self.config.proxy.clone();
-            let proxy_auth = self.config.proxy_auth.clone();
-            let target = self.config.target.clone();
+            let backend = balancer.pick(socket_addr.ip(), &self.stats);
+            let target = self.config.targets[backend].clone();
+            let pool = self.pool.clone();
+            let stats = self.stats.clone();
+            let retry = self.config.retry.clone();
+            let wait_queue = wait_queue.clone();
+            let isolation = isolator.credentials(socket_addr.ip());
+
+            if !stats.allow_connection(backend) {
+                println!(
+                    "Circuit breaker of {:?} is open, rejecting {}",
+                    target, socket_addr
+                );
+                continue;
+            }
 
+            // Counted before the task runs, so the next pick sees it.
+            let active = stats.connection_started(backend);
             self.tasks.push(tokio::spawn(async move {
-                match proxy {
-                    TargetAddr::Ip(s) => {
-                        forward_tcp_to_socks5(socket, s, &proxy_auth, target).await
+                let _active = active;
+                let isolation = isolation.as_ref();
+                let connected = connect_with_retry(&pool, &target, isolation, retry.as_ref()).await;
+                let connected = match (connected, &wait_queue) {
+                    (Err(error), Some(queue)) => {
+                        queue
+                            .wait(&pool, &target, isolation, socket_addr, error)
+                            .await
                     }
-                    TargetAddr::Domain(d, p) => {
-                        forward_tcp_to_socks5(socket, (d.as_str(), p), &proxy_auth, target).await
+                    (connected, _) => connected,
+                };
+                let stream = match connected {
+                    Ok(stream) => {
+                        stats.connection_succeeded(backend);
+                        stream
                     }
-                }
+                    Err(error) => {
+                        stats.connection_failed(backend, &error);
+                        return Err(error);
+                    }
+                };
+                upstream::forward(socket, stream).await
             }));
         }
 
-        // TODO: error handling
-        println!("SERVER try_join_all...");
-        let res = try_join_all(&mut self.tasks).await;
-        println!("SERVER try_join_all done!");
-
-        self.tasks = vec![];
-
-        res?;
+        Ok(())
+    }
 
-        self.state = ForwardServerState::Stopped;
+    fn stop_requested(&mut self) {
+        self.state = ForwardServerState::Stopping;
         // TODO:
-        match self.state_tx.broadcast(STATE_STOPPED) {
-            Ok(_) => {}
-            Err(_) => {}
-        }
-
-        Ok(())
+        let _ = self.state_tx.broadcast(STATE_STOPPING);
     }
 
     pub fn query_state(&self) -> &ForwardServerState {
         &self.state
     }
 
+    /// Returns a handle to the live statistics of this server.
+    pub fn stats(&self) -> ServerStats {
+        self.stats.clone()
+    }
+
+    /// Subscribes to the events of this server from now on.
+    pub fn events(&self) -> broadcast::Receiver<ForwardServerEvent> {
+        self.events.subscribe()
+    }
+
     pub async fn stopped(&mut self) {
         self.wait_till_state(STATE_STOPPED).await;
     }
@@ -1,5 +1,7 @@
 use std::io;
+use std::net::Shutdown;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
 
 pub async fn pipe<R, W>(mut read: R, mut write: W) -> io::Result<()>
 where
@@ -19,3 +21,14 @@ where
     println!("[client] client => here => proxy => server: end");
     Ok(())
 }
+
+/// Shuts down both sockets of a relay that ended. Failures are only logged,
+/// since the peers may have closed them already.
+pub(crate) fn shutdown_both(client: &TcpStream, proxy: &TcpStream) {
+    if let Err(error) = client.shutdown(Shutdown::Both) {
+        eprintln!("[client] client <=> here shutdown with failure: {}", error);
+    }
+    if let Err(error) = proxy.shutdown(Shutdown::Both) {
+        eprintln!("[client] here <=> proxy shutdown with failure: {}", error);
+    }
+}
@@ -0,0 +1,625 @@
+use super::events::ForwardServerEvent;
+use super::health::Health;
+use super::stats::UpstreamStatus;
+use super::upstream::{is_target_error, Upstream, UpstreamStream};
+use super::warm::{WarmPool, WarmPoolConfig};
+use crate::auth::Authentication;
+use crate::http::StatusError;
+use crate::resolver::Resolver;
+use crate::socks4::Socks4Error;
+use crate::socks5::{Socks5Datagram, Socks5Error, Socks5Listener};
+use crate::target_addr::TargetAddr;
+use rand::seq::SliceRandom;
+use std::future::Future;
+use std::io;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// When to stop using an upstream proxy that keeps failing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailoverConfig {
+    /// Consecutive connect or handshake failures after which a proxy is
+    /// skipped.
+    pub max_failures: u32,
+
+    /// How long a failing proxy is skipped before it is tried again.
+    pub cooldown: Duration,
+}
+
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        FailoverConfig {
+            max_failures: 3,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Weight of a new sample in the moving average of the latency.
+const LATENCY_SMOOTHING: f64 = 0.3;
+
+/// Proxies whose latency is within this fraction of the fastest one are
+/// picked at random, so load does not swing between near-equal proxies.
+const LATENCY_TOLERANCE: f64 = 0.2;
+
+/// How the proxy for a new connection is chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum UpstreamSelection {
+    /// The first usable proxy in config order.
+    #[default]
+    Ordered,
+
+    /// The usable proxy with the lowest moving average of the time from the
+    /// start of the connect to the reply to CONNECT, not counting tunnels
+    /// over warm sessions. Proxies without a measurement are tried first.
+    LowestLatency,
+}
+
+impl FromStr for UpstreamSelection {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<UpstreamSelection> {
+        match s.to_ascii_lowercase().as_str() {
+            "ordered" => Ok(UpstreamSelection::Ordered),
+            "lowest-latency" => Ok(UpstreamSelection::LowestLatency),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unknown upstream selection",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct UpstreamState {
+    consecutive_failures: u32,
+    skipped_until: Option<Instant>,
+    health: Health,
+    last_error: Option<String>,
+    latency: Option<Duration>,
+}
+
+impl UpstreamState {
+    fn record_latency(&mut self, sample: Duration) {
+        self.latency = Some(match self.latency {
+            Some(avg) => avg.mul_f64(1.0 - LATENCY_SMOOTHING) + sample.mul_f64(LATENCY_SMOOTHING),
+            None => sample,
+        });
+    }
+}
+
+/// A list of upstream proxies.
+///
+/// Connections go through the proxy picked by the `UpstreamSelection` among
+/// those neither cooling down nor unhealthy, and fail over to the next one on
+/// connect or handshake failure.
+#[derive(Debug)]
+pub(crate) struct UpstreamPool {
+    upstreams: Vec<Upstream>,
+    states: Mutex<Vec<UpstreamState>>,
+    config: FailoverConfig,
+    selection: UpstreamSelection,
+    warm: Option<WarmPool>,
+    resolver: Resolver,
+    events: broadcast::Sender<ForwardServerEvent>,
+}
+
+impl UpstreamPool {
+    pub fn new(
+        upstreams: Vec<Upstream>,
+        config: FailoverConfig,
+        selection: UpstreamSelection,
+        warm: Option<WarmPoolConfig>,
+        resolver: Resolver,
+        events: broadcast::Sender<ForwardServerEvent>,
+    ) -> UpstreamPool {
+        let states = upstreams.iter().map(|_| UpstreamState::default()).collect();
+        let warm = warm.map(|warm| WarmPool::new(warm, upstreams.len()));
+        UpstreamPool {
+            upstreams,
+            states: Mutex::new(states),
+            config,
+            selection,
+            warm,
+            resolver,
+            events,
+        }
+    }
+
+    /// Returns the resolver of proxy hostnames.
+    pub fn resolver(&self) -> &Resolver {
+        &self.resolver
+    }
+
+    /// Returns the ready sessions, if enabled.
+    pub fn warm(&self) -> Option<&WarmPool> {
+        self.warm.as_ref()
+    }
+
+    pub fn upstream(&self, index: usize) -> &Upstream {
+        &self.upstreams[index]
+    }
+
+    pub fn len(&self) -> usize {
+        self.upstreams.len()
+    }
+
+    /// Returns the indexes of the proxies to try, in order.
+    ///
+    /// Proxies cooling down or unhealthy are left out, unless all of them
+    /// are.
+    fn candidates(&self) -> Vec<usize> {
+        let now = Instant::now();
+        let states = self.states.lock().unwrap();
+        let available: Vec<usize> = states
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.health != Health::Unhealthy)
+            .filter(|(_, s)| match s.skipped_until {
+                Some(until) => until <= now,
+                None => true,
+            })
+            .map(|(i, _)| i)
+            .collect();
+
+        let candidates = if available.is_empty() {
+            (0..self.upstreams.len()).collect()
+        } else {
+            available
+        };
+
+        match self.selection {
+            UpstreamSelection::Ordered => candidates,
+            UpstreamSelection::LowestLatency => by_latency(candidates, &states),
+        }
+    }
+
+    fn report_success(&self, index: usize, latency: Option<Duration>) {
+        let mut states = self.states.lock().unwrap();
+        let state = &mut states[index];
+        if let Some(latency) = latency {
+            state.record_latency(latency);
+        }
+        state.consecutive_failures = 0;
+        state.skipped_until = None;
+    }
+
+    fn report_failure(&self, index: usize, error: &io::Error) {
+        let mut states = self.states.lock().unwrap();
+        let state = &mut states[index];
+        state.last_error = Some(error.to_string());
+        state.consecutive_failures += 1;
+        if state.consecutive_failures >= self.config.max_failures {
+            println!(
+                "Upstream {:?} failed {} times, skipping it for {:?}",
+                self.upstreams[index].addr, state.consecutive_failures, self.config.cooldown
+            );
+            state.skipped_until = Some(Instant::now() + self.config.cooldown);
+        }
+    }
+
+    /// Establishes a tunnel to `target`, failing over through the proxies.
+    ///
+    /// `isolation` varies the credentials of SOCKS proxies if set. Warm
+    /// sessions are only used without it.
+    pub async fn connect(
+        &self,
+        target: &TargetAddr,
+        isolation: Option<&Authentication>,
+    ) -> io::Result<UpstreamStream> {
+        self.failover(|index, upstream| async move {
+            let session = match (&self.warm, isolation) {
+                (Some(warm), None) => warm.take(index),
+                _ => None,
+            };
+            let stream = match session {
+                Some(session) => match upstream.connect_session(session, target).await {
+                    Ok(stream) => stream,
+                    // Anything but a reply may come from a session the
+                    // proxy closed as idle.
+                    Err(error) if !is_reply(&error) => {
+                        println!(
+                            "Warm session to {:?} failed: {}, connecting anew",
+                            upstream.addr, error
+                        );
+                        upstream.connect(&self.resolver, target, isolation).await?
+                    }
+                    Err(error) => return Err(error),
+                },
+                None => upstream.connect(&self.resolver, target, isolation).await?,
+            };
+            self.check_auth(
+                upstream,
+                &upstream.auth_for(isolation),
+                stream.auth_method(),
+            );
+            let latency = stream.latency();
+            Ok((stream, latency))
+        })
+        .await
+    }
+
+    /// Sets up a UDP relay for datagrams to `target`, failing over through
+    /// the proxies. Returns the target resolved as the proxy wants it.
+    pub async fn associate(&self, target: &TargetAddr) -> io::Result<(Socks5Datagram, TargetAddr)> {
+        self.failover(|_, upstream| async move {
+            let (datagram, target) = upstream.associate(&self.resolver, target).await?;
+            self.check_auth(upstream, &upstream.auth, Some(datagram.auth_method()));
+            Ok(((datagram, target), None))
+        })
+        .await
+    }
+
+    /// Opens a port for `peer` on a proxy, failing over through the proxies.
+    pub async fn bind(&self, peer: &TargetAddr) -> io::Result<Socks5Listener> {
+        self.failover(|_, upstream| async move {
+            let listener = upstream.bind(&self.resolver, peer).await?;
+            self.check_auth(upstream, &upstream.auth, Some(listener.auth_method()));
+            Ok((listener, None))
+        })
+        .await
+    }
+
+    /// Reports a proxy that selected no authentication although `auth` has
+    /// credentials.
+    fn check_auth(&self, upstream: &Upstream, auth: &Authentication, method: Option<u8>) {
+        if let (Authentication::Password { .. }, Some(0)) = (auth, method) {
+            eprintln!(
+                "Upstream {:?} skipped authentication, its credentials were not used",
+                upstream.addr
+            );
+            // Nobody listening is fine.
+            let _ = self.events.send(ForwardServerEvent::AuthDowngraded {
+                proxy: upstream.addr.clone(),
+            });
+        }
+    }
+
+    /// Runs `attempt` with the candidate proxies in turn until one succeeds.
+    ///
+    /// `attempt` also returns the latency to record for the proxy, if any.
+    async fn failover<'a, T, F, Fut>(&'a self, attempt: F) -> io::Result<T>
+    where
+        F: Fn(usize, &'a Upstream) -> Fut,
+        Fut: Future<Output = io::Result<(T, Option<Duration>)>>,
+    {
+        let mut last_error = None;
+        for index in self.candidates() {
+            match attempt(index, &self.upstreams[index]).await {
+                Ok((value, latency)) => {
+                    self.report_success(index, latency);
+                    return Ok(value);
+                }
+                Err(error) => {
+                    eprintln!(
+                        "Upstream {:?} failed: {}",
+                        self.upstreams[index].addr, error
+                    );
+                    if is_proxy_failure(&error) {
+                        self.report_failure(index, &error);
+                    }
+                    last_error = Some(error);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no upstream proxy configured")
+        }))
+    }
+
+    /// Records the result of a health check, which is the probe latency on
+    /// success.
+    pub fn report_health(&self, index: usize, result: io::Result<Duration>) {
+        let mut states = self.states.lock().unwrap();
+        let state = &mut states[index];
+        let health = match &result {
+            Ok(_) => Health::Healthy,
+            Err(_) => Health::Unhealthy,
+        };
+
+        if health != state.health {
+            let proxy = self.upstreams[index].addr.clone();
+            match &result {
+                Ok(_) => println!("Upstream {:?} is healthy", proxy),
+                Err(error) => eprintln!("Upstream {:?} is unhealthy: {}", proxy, error),
+            }
+            // Nobody listening is fine.
+            let _ = self.events.send(ForwardServerEvent::UpstreamHealth {
+                proxy,
+                health,
+                error: result.as_ref().err().map(|e| e.to_string()),
+            });
+        }
+
+        state.health = health;
+        match result {
+            Ok(latency) => state.record_latency(latency),
+            Err(error) => state.last_error = Some(error.to_string()),
+        }
+    }
+
+    /// Returns the current state of every proxy, in config order.
+    pub fn status(&self) -> Vec<UpstreamStatus> {
+        let now = Instant::now();
+        let states = self.states.lock().unwrap();
+        self.upstreams
+            .iter()
+            .zip(states.iter())
+            .map(|(upstream, state)| UpstreamStatus {
+                addr: upstream.addr.clone(),
+                health: state.health,
+                last_error: state.last_error.clone(),
+                consecutive_failures: state.consecutive_failures,
+                latency: state.latency,
+                cooling_down: match state.skipped_until {
+                    Some(until) => until > now,
+                    None => false,
+                },
+            })
+            .collect()
+    }
+}
+
+/// Whether `error` is the answer of a proxy to a request: any SOCKS5 reply,
+/// a SOCKS4 rejection or an HTTP status saying the target is unreachable.
+fn is_reply(error: &io::Error) -> bool {
+    let inner = match error.get_ref() {
+        Some(inner) => inner,
+        None => return false,
+    };
+    if let Some(error) = inner.downcast_ref::<Socks5Error>() {
+        return error.reply_code().is_some();
+    }
+    if let Some(error) = inner.downcast_ref::<StatusError>() {
+        return error.is_target_unreachable();
+    }
+    inner.is::<Socks4Error>()
+}
+
+/// Whether `error` counts against the proxy: connect, handshake, auth and
+/// protocol errors do, replies about the target and local target resolution
+/// errors do not.
+fn is_proxy_failure(error: &io::Error) -> bool {
+    !is_reply(error) && !is_target_error(error)
+}
+
+/// Whether `error` says the target itself could not be reached: a SOCKS5
+/// reply 3 to 6, a SOCKS4 reply 91, an HTTP 502, 503 or 504, or a local
+/// target error. Anything else may be the fault of the proxy.
+pub(crate) fn is_target_failure(error: &io::Error) -> bool {
+    if is_target_error(error) {
+        return true;
+    }
+    let inner = match error.get_ref() {
+        Some(inner) => inner,
+        None => return false,
+    };
+    if let Some(error) = inner.downcast_ref::<Socks5Error>() {
+        return matches!(error.reply_code(), Some(3..=6));
+    }
+    if let Some(error) = inner.downcast_ref::<StatusError>() {
+        return error.is_target_unreachable();
+    }
+    matches!(
+        inner.downcast_ref::<Socks4Error>(),
+        Some(Socks4Error::Reply(91))
+    )
+}
+
+/// Orders proxies by latency: unmeasured ones first, then the ones close to
+/// the fastest in random order, then the rest from fast to slow.
+fn by_latency(candidates: Vec<usize>, states: &[UpstreamState]) -> Vec<usize> {
+    let (mut measured, mut ordered): (Vec<usize>, Vec<usize>) = candidates
+        .into_iter()
+        .partition(|i| states[*i].latency.is_some());
+
+    measured.sort_by_key(|i| states[*i].latency);
+    if let Some(fastest) = measured.first().and_then(|i| states[*i].latency) {
+        let limit = fastest.mul_f64(1.0 + LATENCY_TOLERANCE);
+        let close = measured
+            .iter()
+            .take_while(|i| states[**i].latency <= Some(limit))
+            .count();
+        measured[..close].shuffle(&mut rand::thread_rng());
+    }
+
+    ordered.extend(measured);
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target_addr::ToTargetAddr;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn pool(n: usize) -> UpstreamPool {
+        let upstreams = (0..n)
+            .map(|i| format!("socks5h://127.0.0.1:{}", 1080 + i).parse().unwrap())
+            .collect();
+        UpstreamPool::new(
+            upstreams,
+            FailoverConfig {
+                max_failures: 2,
+                cooldown: Duration::from_secs(60),
+            },
+            UpstreamSelection::Ordered,
+            None,
+            Resolver::default(),
+            broadcast::channel(1).0,
+        )
+    }
+
+    #[test]
+    fn failing_upstream_is_skipped() {
+        let pool = pool(2);
+        assert_eq!(pool.candidates(), vec![0, 1]);
+
+        let error = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        pool.report_failure(0, &error);
+        assert_eq!(pool.candidates(), vec![0, 1]);
+
+        pool.report_failure(0, &error);
+        assert_eq!(pool.candidates(), vec![1]);
+        assert!(pool.status()[0].cooling_down);
+        assert_eq!(pool.status()[0].last_error.as_ref().unwrap(), "refused");
+
+        pool.report_success(0, Some(Duration::from_millis(10)));
+        assert_eq!(pool.candidates(), vec![0, 1]);
+    }
+
+    #[test]
+    fn all_cooling_down_tries_all() {
+        let pool = pool(2);
+        let error = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        for _ in 0..2 {
+            pool.report_failure(0, &error);
+            pool.report_failure(1, &error);
+        }
+        assert_eq!(pool.candidates(), vec![0, 1]);
+    }
+
+    #[tokio::test]
+    async fn target_errors_do_not_count() {
+        let pool = pool(1);
+        for _ in 0..2 {
+            let result: io::Result<()> = pool
+                .failover(|_, _| async { Err(Socks5Error::Reply(4).into()) })
+                .await;
+            assert!(result.is_err());
+        }
+        assert_eq!(pool.candidates(), vec![0]);
+        assert_eq!(pool.status()[0].consecutive_failures, 0);
+
+        // socks5:// resolves targets locally.
+        let pool = UpstreamPool::new(
+            vec!["socks5://127.0.0.1:1080".parse().unwrap()],
+            FailoverConfig::default(),
+            UpstreamSelection::Ordered,
+            None,
+            Resolver::default(),
+            broadcast::channel(1).0,
+        );
+        let target = ("unresolvable.invalid", 80).to_target_addr().unwrap();
+        assert!(pool.connect(&target, None).await.is_err());
+        assert_eq!(pool.status()[0].consecutive_failures, 0);
+    }
+
+    /// Runs a proxy that answers every request with `reply`.
+    async fn answering_proxy(reply: &'static [u8]) -> SocketAddr {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = [0; 512];
+                let _ = socket.read(&mut request).await;
+                let _ = socket.write_all(reply).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn socks4_and_http_replies_do_not_count() {
+        let socks4 = answering_proxy(&[0, 91, 0, 0, 0, 0, 0, 0]).await;
+        let http = answering_proxy(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await;
+        let pool = UpstreamPool::new(
+            vec![
+                format!("socks4a://{}", socks4).parse().unwrap(),
+                format!("http://{}", http).parse().unwrap(),
+            ],
+            FailoverConfig {
+                max_failures: 1,
+                cooldown: Duration::from_secs(60),
+            },
+            UpstreamSelection::Ordered,
+            None,
+            Resolver::default(),
+            broadcast::channel(1).0,
+        );
+        let target = ("example.com", 80).to_target_addr().unwrap();
+
+        let error = pool.connect(&target, None).await.unwrap_err();
+        assert!(error.get_ref().unwrap().is::<StatusError>());
+        assert_eq!(pool.candidates(), vec![0, 1]);
+        for status in pool.status() {
+            assert_eq!(status.consecutive_failures, 0);
+        }
+    }
+
+    #[test]
+    fn unhealthy_upstream_is_skipped() {
+        let pool = pool(2);
+        pool.report_health(0, Err(io::Error::new(io::ErrorKind::TimedOut, "timeout")));
+        pool.report_health(1, Ok(Duration::from_millis(10)));
+        assert_eq!(pool.candidates(), vec![1]);
+        assert_eq!(pool.status()[0].health, Health::Unhealthy);
+        assert_eq!(pool.status()[1].health, Health::Healthy);
+
+        pool.report_health(0, Ok(Duration::from_millis(10)));
+        assert_eq!(pool.candidates(), vec![0, 1]);
+    }
+
+    #[test]
+    fn health_changes_are_reported() {
+        let (events, mut rx) = broadcast::channel(4);
+        let pool = UpstreamPool::new(
+            vec!["socks5h://127.0.0.1:1080".parse().unwrap()],
+            FailoverConfig::default(),
+            UpstreamSelection::Ordered,
+            None,
+            Resolver::default(),
+            events,
+        );
+        let error = || Err(io::Error::new(io::ErrorKind::TimedOut, "timeout"));
+        pool.report_health(0, error());
+        pool.report_health(0, error());
+
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            ForwardServerEvent::UpstreamHealth {
+                proxy: pool.upstream(0).addr.clone(),
+                health: Health::Unhealthy,
+                error: Some("timeout".to_string()),
+            }
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn latency_moving_average() {
+        let mut state = UpstreamState::default();
+        state.record_latency(Duration::from_millis(100));
+        assert_eq!(state.latency, Some(Duration::from_millis(100)));
+        state.record_latency(Duration::from_millis(200));
+        assert_eq!(state.latency, Some(Duration::from_millis(130)));
+    }
+
+    #[test]
+    fn lowest_latency_order() {
+        let mut states: Vec<UpstreamState> = (0..4).map(|_| UpstreamState::default()).collect();
+        states[0].latency = Some(Duration::from_millis(300));
+        states[1].latency = Some(Duration::from_millis(100));
+        states[2].latency = Some(Duration::from_millis(110));
+
+        for _ in 0..16 {
+            let order = by_latency(vec![0, 1, 2, 3], &states);
+            assert_eq!(order[0], 3);
+            assert!(order[1..3].contains(&1) && order[1..3].contains(&2));
+            assert_eq!(order[3], 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_pool_fails() {
+        let pool = pool(0);
+        let target = ("127.0.0.1", 80).to_target_addr().unwrap();
+        assert!(pool.connect(&target, None).await.is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use crate::socks5::Socks5Error;
+use rand::Rng;
+use std::io;
+use std::time::Duration;
+
+/// How often and how fast the upstream leg of a connection is retried.
+///
+/// Retries happen before anything is read from the client, so a client never
+/// notices a retried connect except for the delay.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one.
+    pub max_attempts: u32,
+
+    /// Delay before the first retry.
+    pub initial_backoff: Duration,
+
+    /// Upper bound of the delay between two attempts.
+    pub max_backoff: Duration,
+
+    /// Factor the delay grows by after each retry.
+    pub multiplier: f64,
+
+    /// SOCKS5 reply codes worth a retry. Connection refused, reset, aborted
+    /// and timed out I/O errors are always retried.
+    pub retryable_replies: Vec<u8>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2.0,
+            // general SOCKS server failure, connection refused, TTL expired
+            retryable_replies: vec![1, 5, 6],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns whether a failed connect is worth another attempt.
+    pub fn is_retryable(&self, error: &io::Error) -> bool {
+        let reply = error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<Socks5Error>())
+            .and_then(Socks5Error::reply_code);
+        if let Some(code) = reply {
+            return self.retryable_replies.contains(&code);
+        }
+
+        matches!(
+            error.kind(),
+            io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::TimedOut
+        )
+    }
+
+    /// Returns the delay before retry number `retry`, counting from 0.
+    ///
+    /// The exponential delay is capped at `max_backoff` and then scaled by a
+    /// random factor between 0.5 and 1, so clients failing together do not
+    /// retry together.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let jitter = rand::thread_rng().gen_range(0.5, 1.0);
+        self.max_delay(retry).mul_f64(jitter)
+    }
+
+    fn max_delay(&self, retry: u32) -> Duration {
+        let factor = self.multiplier.max(1.0).powi(retry as i32);
+        let max = self.max_backoff.as_secs_f64();
+        Duration::from_secs_f64((self.initial_backoff.as_secs_f64() * factor).min(max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retryable_errors() {
+        let policy = RetryPolicy::default();
+        let reply = |code| io::Error::from(Socks5Error::Reply(code));
+
+        assert!(policy.is_retryable(&reply(1)));
+        assert!(policy.is_retryable(&reply(5)));
+        assert!(!policy.is_retryable(&reply(2)));
+        assert!(policy.is_retryable(&io::Error::new(io::ErrorKind::ConnectionRefused, "refused")));
+        assert!(!policy.is_retryable(&io::Error::new(io::ErrorKind::PermissionDenied, "denied")));
+    }
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        let policy = RetryPolicy {
+            max_backoff: Duration::from_millis(350),
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.max_delay(0), Duration::from_millis(100));
+        assert_eq!(policy.max_delay(1), Duration::from_millis(200));
+        assert_eq!(policy.max_delay(2), Duration::from_millis(350));
+
+        for retry in 0..4 {
+            let delay = policy.backoff(retry);
+            assert!(delay >= policy.max_delay(retry) / 2 && delay <= policy.max_delay(retry));
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use super::balance::Balancer;
+use super::events::ForwardServerEvent;
+use super::upstream::{self, target_error, UpstreamStream};
+use super::ForwardServer;
+use crate::happy_eyeballs;
+use crate::target_addr::TargetAddr;
+use futures::future::{pending, Fuse, FutureExt};
+use futures::{pin_mut, select};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::delay_for;
+
+/// Time before asking again after no proxy could open a port, or the port
+/// failed before a connection came in.
+const REBIND_DELAY: Duration = Duration::from_secs(5);
+
+impl ForwardServer {
+    /// Keeps a port open on the proxy for `peer` and relays every connection
+    /// it accepts to a target, until the stopper fires.
+    pub(super) async fn serve_reverse<S>(
+        &mut self,
+        stopper: &mut Option<Fuse<S>>,
+        balancer: &Balancer,
+        peer: TargetAddr,
+    ) -> io::Result<()>
+    where
+        S: Future + Unpin,
+    {
+        let stop = async {
+            match stopper.as_mut() {
+                Some(s) => {
+                    s.await;
+                }
+                None => pending().await,
+            }
+        }
+        .fuse();
+        pin_mut!(stop);
+
+        let pool = self.pool.clone();
+        loop {
+            let bound = select! {
+                bound = pool.bind(&peer).fuse() => bound,
+                _ = stop => break,
+            };
+            let listener = match bound {
+                Ok(listener) => listener,
+                Err(error) => {
+                    eprintln!(
+                        "Reverse bind failed: {}, retry in {:?}",
+                        error, REBIND_DELAY
+                    );
+                    select! {
+                        _ = delay_for(REBIND_DELAY).fuse() => continue,
+                        _ = stop => break,
+                    }
+                }
+            };
+
+            let addr = listener.proxy_addr().clone();
+            println!("Proxy listening at {:?} for {:?}", addr, peer);
+            // Nobody listening is fine.
+            let _ = self.events.send(ForwardServerEvent::ReverseBound { addr });
+
+            let accepted = select! {
+                accepted = listener.accept().fuse() => accepted,
+                _ = stop => break,
+            };
+            let (stream, peer_addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(error) => {
+                    eprintln!(
+                        "Reverse accept failed: {}, retry in {:?}",
+                        error, REBIND_DELAY
+                    );
+                    select! {
+                        _ = delay_for(REBIND_DELAY).fuse() => continue,
+                        _ = stop => break,
+                    }
+                }
+            };
+            println!("Accepted at proxy from {:?}", peer_addr);
+
+            let client_ip = match &peer_addr {
+                TargetAddr::Ip(addr) => addr.ip(),
+                TargetAddr::Domain(..) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            };
+            let backend = balancer.pick(client_ip, &self.stats);
+            let target = self.config.targets[backend].clone();
+            let stats = self.stats.clone();
+            if !stats.allow_connection(backend) {
+                println!(
+                    "Circuit breaker of {:?} is open, rejecting {:?}",
+                    target, peer_addr
+                );
+                continue;
+            }
+
+            // Counted before the task runs, so the next pick sees it.
+            let active = stats.connection_started(backend);
+            self.tasks.push(tokio::spawn(async move {
+                let _active = active;
+                let local = match connect_local(&target).await {
+                    Ok(local) => {
+                        stats.connection_succeeded(backend);
+                        local
+                    }
+                    Err(error) => {
+                        stats.connection_failed(backend, &error);
+                        return Err(error);
+                    }
+                };
+                upstream::forward(local, UpstreamStream::from(stream.into_inner())).await
+            }));
+        }
+
+        self.stop_requested();
+        Ok(())
+    }
+}
+
+/// Connects to `target` directly, failures are target errors.
+async fn connect_local(target: &TargetAddr) -> io::Result<TcpStream> {
+    let connected = match target {
+        TargetAddr::Ip(addr) => TcpStream::connect(addr).await,
+        TargetAddr::Domain(domain, port) => happy_eyeballs::connect((domain.as_str(), *port)).await,
+    };
+    connected.map_err(target_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{ForwardMode, ForwardServerConfig};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+
+    /// Reads a BIND request without authentication and answers it with the
+    /// address the proxy listens at.
+    async fn accept_bind(socket: &mut TcpStream) {
+        let mut greeting = [0; 3];
+        socket.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(greeting, [5, 1, 0]);
+        socket.write_all(&[5, 0]).await.unwrap();
+
+        let mut request = [0; 10];
+        socket.read_exact(&mut request).await.unwrap();
+        assert_eq!(request, [5, 2, 0, 1, 127, 0, 0, 1, 0x30, 0x39]);
+        socket
+            .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x1f, 0x90])
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn relays_to_target_and_binds_again() {
+        let mut target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = target.accept().await.unwrap();
+            let mut buf = [0; 4];
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            socket.write_all(b"pong").await.unwrap();
+        });
+
+        let mut proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let (rebound_tx, rebound) = oneshot::channel();
+        let proxy_task = tokio::spawn(async move {
+            let (mut socket, _) = proxy.accept().await.unwrap();
+            accept_bind(&mut socket).await;
+            // The peer connects to the port.
+            socket
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x30, 0x39])
+                .await
+                .unwrap();
+            socket.write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"pong");
+            drop(socket);
+
+            // The used port is replaced with a new one.
+            let (mut socket, _) = proxy.accept().await.unwrap();
+            accept_bind(&mut socket).await;
+            rebound_tx.send(()).unwrap();
+            let _ = socket.read_u8().await;
+        });
+
+        let mut config = ForwardServerConfig::new(
+            "127.0.0.1:0".parse().unwrap(),
+            vec![format!("socks5://{}", proxy_addr).parse().unwrap()],
+            vec![TargetAddr::Ip(target_addr)],
+        );
+        config.mode = ForwardMode::Reverse {
+            peer: TargetAddr::Ip("127.0.0.1:12345".parse().unwrap()),
+        };
+        let mut server = ForwardServer::new(config);
+        server.start(Some(rebound)).await.unwrap();
+        proxy_task.await.unwrap();
+    }
+}
@@ -0,0 +1,271 @@
+use super::breaker::{BreakerState, CircuitBreaker, CircuitBreakerConfig};
+use super::events::ForwardServerEvent;
+use super::health::Health;
+use super::pool::{is_target_failure, UpstreamPool};
+use crate::target_addr::TargetAddr;
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+#[derive(Debug)]
+struct BackendCounters {
+    target: TargetAddr,
+    active: AtomicUsize,
+    total: AtomicU64,
+    failed: AtomicU64,
+    rejected: AtomicU64,
+    breaker: Option<CircuitBreaker>,
+}
+
+#[derive(Debug)]
+struct StatsInner {
+    backends: Vec<BackendCounters>,
+    pool: Arc<UpstreamPool>,
+}
+
+/// A snapshot of the counters of one backend target.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackendStats {
+    pub target: TargetAddr,
+
+    /// Connections currently being forwarded to this target.
+    pub active_connections: usize,
+
+    /// Connections assigned to this target since the server was created.
+    pub total_connections: u64,
+
+    /// Connections that could not be established through any upstream proxy.
+    pub failed_connections: u64,
+
+    /// Connections refused locally while the circuit breaker was open.
+    pub rejected_connections: u64,
+
+    /// Always `Closed` if no circuit breaker is configured.
+    pub breaker: BreakerState,
+}
+
+/// A snapshot of the state of one upstream proxy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpstreamStatus {
+    pub addr: TargetAddr,
+    pub health: Health,
+
+    /// The latest connect, handshake or health check error.
+    pub last_error: Option<String>,
+    pub consecutive_failures: u32,
+
+    /// Moving average of the time from the start of the connect to the
+    /// reply to CONNECT, or of the health check probe.
+    pub latency: Option<Duration>,
+
+    /// Whether the proxy is skipped after failing too often.
+    pub cooling_down: bool,
+}
+
+/// A handle to the live statistics of a `ForwardServer`.
+///
+/// It is cheap to clone and stays valid while the server is running.
+#[derive(Debug, Clone)]
+pub struct ServerStats {
+    inner: Arc<StatsInner>,
+}
+
+impl ServerStats {
+    pub(crate) fn new(
+        targets: &[TargetAddr],
+        pool: Arc<UpstreamPool>,
+        breaker: Option<&CircuitBreakerConfig>,
+        events: &broadcast::Sender<ForwardServerEvent>,
+    ) -> ServerStats {
+        let backends = targets
+            .iter()
+            .map(|target| BackendCounters {
+                target: target.clone(),
+                active: AtomicUsize::new(0),
+                total: AtomicU64::new(0),
+                failed: AtomicU64::new(0),
+                rejected: AtomicU64::new(0),
+                breaker: breaker.map(|config| {
+                    CircuitBreaker::new(target.clone(), config.clone(), events.clone())
+                }),
+            })
+            .collect();
+
+        ServerStats {
+            inner: Arc::new(StatsInner { backends, pool }),
+        }
+    }
+
+    /// Returns the counters of every backend target, in config order.
+    pub fn backends(&self) -> Vec<BackendStats> {
+        self.inner
+            .backends
+            .iter()
+            .map(|b| BackendStats {
+                target: b.target.clone(),
+                active_connections: b.active.load(Ordering::Relaxed),
+                total_connections: b.total.load(Ordering::Relaxed),
+                failed_connections: b.failed.load(Ordering::Relaxed),
+                rejected_connections: b.rejected.load(Ordering::Relaxed),
+                breaker: match &b.breaker {
+                    Some(breaker) => breaker.state(),
+                    None => BreakerState::Closed,
+                },
+            })
+            .collect()
+    }
+
+    /// Returns the state of every upstream proxy, in config order.
+    pub fn upstreams(&self) -> Vec<UpstreamStatus> {
+        self.inner.pool.status()
+    }
+
+    pub(crate) fn active_connections(&self, backend: usize) -> usize {
+        self.inner.backends[backend].active.load(Ordering::Relaxed)
+    }
+
+    /// Counts a new connection to `backend` until the returned guard is
+    /// dropped.
+    pub(crate) fn connection_started(&self, backend: usize) -> ActiveConnection {
+        let counters = &self.inner.backends[backend];
+        counters.total.fetch_add(1, Ordering::Relaxed);
+        counters.active.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection {
+            stats: self.clone(),
+            backend,
+        }
+    }
+
+    /// Returns whether the circuit breaker of `backend` would reject a new
+    /// client now.
+    pub(crate) fn is_rejecting(&self, backend: usize) -> bool {
+        match &self.inner.backends[backend].breaker {
+            Some(breaker) => breaker.is_rejecting(),
+            None => false,
+        }
+    }
+
+    /// Asks the circuit breaker of `backend` whether a new client may be
+    /// forwarded, and counts the client as rejected if not.
+    pub(crate) fn allow_connection(&self, backend: usize) -> bool {
+        let counters = &self.inner.backends[backend];
+        let allowed = match &counters.breaker {
+            Some(breaker) => breaker.allow(),
+            None => true,
+        };
+        if !allowed {
+            counters.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    pub(crate) fn connection_succeeded(&self, backend: usize) {
+        if let Some(breaker) = &self.inner.backends[backend].breaker {
+            breaker.record_success();
+        }
+    }
+
+    /// Counts a connection to `backend` that could not be established. Only
+    /// target failures, see `is_target_failure`, count for the circuit
+    /// breaker; proxy failures are left to the upstream pool.
+    pub(crate) fn connection_failed(&self, backend: usize, error: &io::Error) {
+        let counters = &self.inner.backends[backend];
+        counters.failed.fetch_add(1, Ordering::Relaxed);
+        if let Some(breaker) = &counters.breaker {
+            if is_target_failure(error) {
+                breaker.record_failure();
+            } else {
+                breaker.record_inconclusive();
+            }
+        }
+    }
+}
+
+/// Decrements the active connection count of a backend when dropped.
+pub(crate) struct ActiveConnection {
+    stats: ServerStats,
+    backend: usize,
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.stats.inner.backends[self.backend]
+            .active
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolver::Resolver;
+    use crate::server::{FailoverConfig, UpstreamSelection};
+    use crate::target_addr::ToTargetAddr;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Runs a SOCKS5 proxy that answers the greeting with `method` and a
+    /// CONNECT with `reply`.
+    async fn proxy(method: u8, reply: u8) -> SocketAddr {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut greeting = [0; 3];
+                socket.read_exact(&mut greeting).await.unwrap();
+                socket.write_all(&[5, method]).await.unwrap();
+                let mut request = [0; 10];
+                if socket.read_exact(&mut request).await.is_ok() {
+                    let _ = socket.write_all(&[5, reply, 0, 1, 0, 0, 0, 0, 0, 0]).await;
+                }
+            }
+        });
+        addr
+    }
+
+    async fn failed_connect(proxy: SocketAddr) -> BreakerState {
+        let upstream = format!("socks5h://{}", proxy).parse().unwrap();
+        let pool = Arc::new(UpstreamPool::new(
+            vec![upstream],
+            FailoverConfig::default(),
+            UpstreamSelection::default(),
+            None,
+            Resolver::default(),
+            broadcast::channel(1).0,
+        ));
+        let targets = [("127.0.0.1", 80).to_target_addr().unwrap()];
+        let config = CircuitBreakerConfig {
+            failure_threshold: 1,
+            open_for: Duration::from_secs(60),
+        };
+        let stats = ServerStats::new(
+            &targets,
+            pool.clone(),
+            Some(&config),
+            &broadcast::channel(1).0,
+        );
+
+        assert!(stats.allow_connection(0));
+        let error = pool.connect(&targets[0], None).await.unwrap_err();
+        stats.connection_failed(0, &error);
+        assert_eq!(stats.backends()[0].failed_connections, 1);
+        stats.backends()[0].breaker
+    }
+
+    #[tokio::test]
+    async fn proxy_failure_does_not_open_breaker() {
+        // No acceptable auth method.
+        let proxy = proxy(0xff, 0).await;
+        assert_eq!(failed_connect(proxy).await, BreakerState::Closed);
+    }
+
+    #[tokio::test]
+    async fn refused_target_opens_breaker() {
+        let proxy = proxy(0, 5).await;
+        assert_eq!(failed_connect(proxy).await, BreakerState::Open);
+    }
+}
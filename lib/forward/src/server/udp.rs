@@ -0,0 +1,341 @@
+use super::balance::Balancer;
+use super::pool::UpstreamPool;
+use super::stats::ServerStats;
+use super::{stopping, ForwardServer};
+use crate::socks5::Socks5Datagram;
+use crate::target_addr::TargetAddr;
+use futures::future::{pending, Fuse, FutureExt};
+use futures::{pin_mut, select};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::udp::SendHalf;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::time::delay_for;
+
+/// The largest payload of a UDP datagram.
+const MAX_DATAGRAM_LEN: usize = 65535;
+
+/// Datagrams from a client buffered while its relay is busy or being set up.
+/// Further ones are dropped.
+const SESSION_QUEUE_LEN: usize = 64;
+
+/// The relay of one client address.
+struct Session {
+    id: u64,
+    tx: mpsc::Sender<Vec<u8>>,
+}
+
+enum Event {
+    Datagram(io::Result<(usize, SocketAddr)>),
+    Expired(Option<(SocketAddr, u64)>),
+    Stop,
+}
+
+impl ForwardServer {
+    /// Receives datagrams and relays them per client address until the
+    /// stopper fires.
+    pub(super) async fn serve_udp<S>(
+        &mut self,
+        stopper: &mut Option<Fuse<S>>,
+        balancer: &Balancer,
+        idle_timeout: Duration,
+    ) -> io::Result<()>
+    where
+        S: Future + Unpin,
+    {
+        let socket = UdpSocket::bind(self.config.bind_addr).await?;
+        let (mut socket, reply) = socket.split();
+        let reply = Arc::new(Mutex::new(reply));
+
+        let (expired_tx, mut expired_rx) = mpsc::unbounded_channel();
+        let mut sessions: HashMap<SocketAddr, Session> = HashMap::new();
+        let mut next_id = 0;
+        let mut buf = vec![0; MAX_DATAGRAM_LEN];
+
+        let stop = async {
+            match stopper.as_mut() {
+                Some(s) => {
+                    s.await;
+                }
+                None => pending().await,
+            }
+        }
+        .fuse();
+        pin_mut!(stop);
+
+        loop {
+            let event = select! {
+                received = socket.recv_from(&mut buf).fuse() => Event::Datagram(received),
+                expired = expired_rx.recv().fuse() => Event::Expired(expired),
+                _ = stop => Event::Stop,
+            };
+
+            let (len, client) = match event {
+                Event::Datagram(Ok(received)) => received,
+                Event::Datagram(Err(error)) => {
+                    eprintln!("UDP receive failed: {}", error);
+                    continue;
+                }
+                Event::Expired(Some((client, id))) => {
+                    if sessions.get(&client).map(|s| s.id) == Some(id) {
+                        sessions.remove(&client);
+                    }
+                    continue;
+                }
+                Event::Expired(None) => continue,
+                Event::Stop => {
+                    self.stop_requested();
+                    break;
+                }
+            };
+            let data = buf[..len].to_vec();
+
+            if let Some(session) = sessions.get_mut(&client) {
+                // A full queue drops the datagram, as the network would. A
+                // closed one belongs to a session about to be removed.
+                let _ = session.tx.try_send(data);
+                continue;
+            }
+
+            let backend = balancer.pick(client.ip(), &self.stats);
+            let target = self.config.targets[backend].clone();
+            if !self.stats.allow_connection(backend) {
+                println!(
+                    "Circuit breaker of {:?} is open, dropping datagram from {}",
+                    target, client
+                );
+                continue;
+            }
+
+            println!("New UDP session from {}", client);
+            let (mut tx, rx) = mpsc::channel(SESSION_QUEUE_LEN);
+            let _ = tx.try_send(data);
+            next_id += 1;
+            sessions.insert(client, Session { id: next_id, tx });
+
+            let session = UdpSession {
+                id: next_id,
+                client,
+                backend,
+                target,
+                pool: self.pool.clone(),
+                stats: self.stats.clone(),
+                reply: reply.clone(),
+                idle_timeout,
+                expired: expired_tx.clone(),
+            };
+            let state_rx = self.state_rx.clone();
+            // Counted before the task runs, so the next pick sees it.
+            let active = self.stats.connection_started(backend);
+            self.tasks.push(tokio::spawn(async move {
+                let _active = active;
+                session.run(rx, state_rx).await
+            }));
+        }
+
+        Ok(())
+    }
+}
+
+struct UdpSession {
+    id: u64,
+    client: SocketAddr,
+    backend: usize,
+    target: TargetAddr,
+    pool: Arc<UpstreamPool>,
+    stats: ServerStats,
+    reply: Arc<Mutex<SendHalf>>,
+    idle_timeout: Duration,
+    expired: mpsc::UnboundedSender<(SocketAddr, u64)>,
+}
+
+enum SessionEvent {
+    FromClient(Option<Vec<u8>>),
+    FromTarget(io::Result<usize>),
+    Idle,
+    Stop,
+}
+
+impl UdpSession {
+    /// Relays datagrams between the client and the target until the session
+    /// is idle or the server stops.
+    async fn run(
+        self,
+        mut rx: mpsc::Receiver<Vec<u8>>,
+        mut state_rx: watch::Receiver<u8>,
+    ) -> io::Result<()> {
+        let result = match self.pool.associate(&self.target).await {
+            Ok((datagram, target)) => {
+                self.stats.connection_succeeded(self.backend);
+                self.relay(datagram, &target, &mut rx, &mut state_rx).await
+            }
+            Err(error) => {
+                eprintln!("UDP session from {} failed: {}", self.client, error);
+                self.stats.connection_failed(self.backend, &error);
+                Ok(())
+            }
+        };
+
+        // The server loop may already be gone when stopping.
+        let _ = self.expired.send((self.client, self.id));
+        result
+    }
+
+    async fn relay(
+        &self,
+        mut datagram: Socks5Datagram,
+        target: &TargetAddr,
+        rx: &mut mpsc::Receiver<Vec<u8>>,
+        state_rx: &mut watch::Receiver<u8>,
+    ) -> io::Result<()> {
+        let mut buf = vec![0; MAX_DATAGRAM_LEN];
+        loop {
+            let event = select! {
+                data = rx.recv().fuse() => SessionEvent::FromClient(data),
+                received = datagram.recv_from(&mut buf).fuse() => {
+                    SessionEvent::FromTarget(received.map(|(len, _)| len))
+                }
+                _ = delay_for(self.idle_timeout).fuse() => SessionEvent::Idle,
+                _ = stopping(state_rx).fuse() => SessionEvent::Stop,
+            };
+
+            match event {
+                SessionEvent::FromClient(Some(data)) => {
+                    datagram.send_to(&data, target.clone()).await?;
+                }
+                SessionEvent::FromTarget(received) => {
+                    let len = received?;
+                    let mut reply = self.reply.lock().await;
+                    reply.send_to(&buf[..len], &self.client).await?;
+                }
+                SessionEvent::Idle => {
+                    println!("UDP session from {} expired", self.client);
+                    return Ok(());
+                }
+                SessionEvent::FromClient(None) | SessionEvent::Stop => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{ForwardMode, ForwardServerConfig};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+    use tokio::time::timeout;
+
+    enum ProxyEvent {
+        Datagram(io::Result<(usize, SocketAddr)>),
+        Closed,
+    }
+
+    /// Runs a SOCKS5 proxy whose UDP relays echo every datagram. Returns its
+    /// address and the number of associations it granted.
+    async fn echo_proxy() -> (SocketAddr, Arc<AtomicUsize>) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let associations = Arc::new(AtomicUsize::new(0));
+        let counter = associations.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut control, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut greeting = [0; 3];
+                    control.read_exact(&mut greeting).await.unwrap();
+                    control.write_all(&[5, 0]).await.unwrap();
+                    let mut request = [0; 10];
+                    control.read_exact(&mut request).await.unwrap();
+                    assert_eq!(request[1], 3);
+
+                    let mut relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                    let port = relay.local_addr().unwrap().port().to_be_bytes();
+                    control
+                        .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, port[0], port[1]])
+                        .await
+                        .unwrap();
+
+                    let mut buf = vec![0; MAX_DATAGRAM_LEN];
+                    loop {
+                        let event = select! {
+                            received = relay.recv_from(&mut buf).fuse() => {
+                                ProxyEvent::Datagram(received)
+                            }
+                            _ = control.read_u8().fuse() => ProxyEvent::Closed,
+                        };
+                        match event {
+                            // The header names the target, which is also
+                            // the source of the answer.
+                            ProxyEvent::Datagram(received) => {
+                                let (len, from) = received.unwrap();
+                                relay.send_to(&buf[..len], &from).await.unwrap();
+                            }
+                            ProxyEvent::Closed => return,
+                        }
+                    }
+                });
+            }
+        });
+        (addr, associations)
+    }
+
+    async fn echo(client: &mut UdpSocket, server: SocketAddr, data: &[u8]) {
+        client.send_to(data, &server).await.unwrap();
+        let mut buf = [0; 64];
+        let received = timeout(Duration::from_secs(5), client.recv_from(&mut buf));
+        let (len, from) = received.await.unwrap().unwrap();
+        assert_eq!(from, server);
+        assert_eq!(&buf[..len], data);
+    }
+
+    #[tokio::test]
+    async fn sessions_are_reused_per_client_until_idle() {
+        let (proxy, associations) = echo_proxy().await;
+        // A port that is free, most likely still when the server binds it.
+        let server_addr = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let mut config = ForwardServerConfig::new(
+            server_addr,
+            vec![format!("socks5h://{}", proxy).parse().unwrap()],
+            vec![TargetAddr::Ip("127.0.0.1:53".parse().unwrap())],
+        );
+        config.mode = ForwardMode::Udp {
+            idle_timeout: Duration::from_millis(200),
+        };
+        let mut server = ForwardServer::new(config);
+        let stats = server.stats();
+        let (stop, stopper) = oneshot::channel::<()>();
+        let running = tokio::spawn(async move { server.start(Some(stopper)).await });
+        delay_for(Duration::from_millis(50)).await;
+
+        let mut client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        echo(&mut client, server_addr, b"one").await;
+        echo(&mut client, server_addr, b"two").await;
+        assert_eq!(associations.load(Ordering::SeqCst), 1);
+        assert_eq!(stats.backends()[0].total_connections, 1);
+        assert_eq!(stats.backends()[0].active_connections, 1);
+
+        delay_for(Duration::from_millis(400)).await;
+        assert_eq!(stats.backends()[0].active_connections, 0);
+
+        echo(&mut client, server_addr, b"three").await;
+        assert_eq!(associations.load(Ordering::SeqCst), 2);
+        assert_eq!(stats.backends()[0].total_connections, 2);
+
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+}
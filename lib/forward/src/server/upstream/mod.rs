@@ -0,0 +1,429 @@
+use super::isolation::isolate;
+use super::{pipe, shutdown_both};
+use crate::auth::Authentication;
+use crate::happy_eyeballs;
+use crate::http::HttpStream;
+use crate::resolver::Resolver;
+use crate::socks4::Socks4Stream;
+use crate::socks5::{
+    AuthPolicy, ConnectOptions, Socks5Datagram, Socks5Error, Socks5Listener, Socks5Session,
+    Timeouts,
+};
+use crate::target_addr::{Resolution, TargetAddr};
+#[cfg(feature = "tls")]
+use crate::tls::TlsConfig;
+use futures::try_join;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use std::{error, fmt, io};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+mod stream;
+pub(crate) use stream::ProxyStream;
+
+mod url;
+
+/// The protocol spoken with the upstream proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ProxyProtocol {
+    #[default]
+    Socks5,
+
+    /// SOCKS4, or SOCKS4a for domain targets.
+    ///
+    /// The username of `Authentication::Password` is sent as the user id;
+    /// SOCKS4 has no password.
+    Socks4,
+
+    /// HTTP/1.1 CONNECT.
+    Http,
+}
+
+impl FromStr for ProxyProtocol {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<ProxyProtocol> {
+        match s.to_ascii_lowercase().as_str() {
+            "socks5" | "socks5h" => Ok(ProxyProtocol::Socks5),
+            "socks4" | "socks4a" => Ok(ProxyProtocol::Socks4),
+            "http" => Ok(ProxyProtocol::Http),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unknown proxy protocol",
+            )),
+        }
+    }
+}
+
+/// An upstream proxy: how to reach it and how to talk to it.
+///
+/// Usually parsed from a proxy URL, see the `FromStr` implementation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Upstream {
+    pub protocol: ProxyProtocol,
+    pub addr: TargetAddr,
+    pub auth: Authentication,
+
+    /// Where domain name targets are resolved before being sent to this
+    /// proxy.
+    pub resolution: Resolution,
+
+    /// Connect, handshake and reply deadlines, only used with SOCKS5.
+    pub timeouts: Timeouts,
+
+    /// Whether a SOCKS5 proxy may skip the configured credentials.
+    pub auth_policy: AuthPolicy,
+
+    /// Speak TLS to the proxy if set. Only SOCKS5 proxies in TCP forwarding
+    /// support it.
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+}
+
+/// An established tunnel through an upstream proxy.
+#[derive(Debug)]
+pub(crate) struct UpstreamStream {
+    socket: ProxyStream,
+
+    /// Bytes from the target that were read while establishing the tunnel.
+    buffered: Vec<u8>,
+
+    /// The auth method a SOCKS5 proxy selected.
+    auth_method: Option<u8>,
+
+    /// The time from the start of the connect to the proxy until its reply,
+    /// `None` over a warm session.
+    latency: Option<Duration>,
+}
+
+impl Upstream {
+    /// Establishes a tunnel to `target` through this proxy, whose hostname
+    /// is looked up with `resolver`.
+    ///
+    /// `isolation` varies the credentials of a SOCKS proxy if set, see
+    /// `auth_for`.
+    pub(crate) async fn connect(
+        &self,
+        resolver: &Resolver,
+        target: &TargetAddr,
+        isolation: Option<&Authentication>,
+    ) -> io::Result<UpstreamStream> {
+        let target = resolve_target(target, self.resolution).await?;
+        let auth = self.auth_for(isolation);
+        let proxy = self.proxy_addrs(resolver).await?;
+        let started = Instant::now();
+        let mut stream = match self.protocol {
+            ProxyProtocol::Socks5 => {
+                let session = self.handshake(&proxy, &auth).await?;
+                self.request(session, target).await?
+            }
+            ProxyProtocol::Socks4 => {
+                self.require_plain("SOCKS4")?;
+                let user_id = match &auth {
+                    Authentication::Password { username, .. } => username.as_str(),
+                    Authentication::None => "",
+                };
+                let socket = Socks4Stream::connect(proxy.as_slice(), target, user_id)
+                    .await?
+                    .into_inner();
+                UpstreamStream::from(socket)
+            }
+            ProxyProtocol::Http => {
+                self.require_plain("HTTP CONNECT")?;
+                let (socket, buffered) = HttpStream::connect(proxy.as_slice(), target, &self.auth)
+                    .await?
+                    .into_parts();
+                UpstreamStream {
+                    buffered,
+                    ..UpstreamStream::from(socket)
+                }
+            }
+        };
+        stream.latency = Some(started.elapsed());
+        Ok(stream)
+    }
+
+    /// Returns the credentials a connection with `isolation` uses: for SOCKS
+    /// proxies the configured ones combined with `isolation`, otherwise the
+    /// configured ones.
+    pub(crate) fn auth_for(&self, isolation: Option<&Authentication>) -> Authentication {
+        match (isolation, self.protocol) {
+            (Some(isolation), ProxyProtocol::Socks5) | (Some(isolation), ProxyProtocol::Socks4) => {
+                isolate(&self.auth, isolation)
+            }
+            _ => self.auth.clone(),
+        }
+    }
+
+    /// Connects and authenticates to this proxy, which must speak SOCKS5,
+    /// ahead of a request.
+    pub(crate) async fn open_session(
+        &self,
+        resolver: &Resolver,
+    ) -> io::Result<Socks5Session<ProxyStream>> {
+        if self.protocol != ProxyProtocol::Socks5 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "warm sessions need a SOCKS5 proxy",
+            ));
+        }
+
+        let proxy = self.proxy_addrs(resolver).await?;
+        self.handshake(&proxy, &self.auth).await
+    }
+
+    /// Establishes a tunnel to `target` over a session opened with
+    /// `open_session`.
+    pub(crate) async fn connect_session(
+        &self,
+        session: Socks5Session<ProxyStream>,
+        target: &TargetAddr,
+    ) -> io::Result<UpstreamStream> {
+        let target = resolve_target(target, self.resolution).await?;
+        self.request(session, target).await
+    }
+
+    /// Sends a CONNECT to `target`, already resolved, over `session`.
+    async fn request(
+        &self,
+        session: Socks5Session<ProxyStream>,
+        target: TargetAddr,
+    ) -> io::Result<UpstreamStream> {
+        let options = self.socks5_options(Resolution::Remote);
+        let stream = session.connect(target, &options).await?;
+        let auth_method = Some(stream.auth_method());
+        Ok(UpstreamStream {
+            socket: stream.into_inner(),
+            buffered: vec![],
+            auth_method,
+            latency: None,
+        })
+    }
+
+    /// Sets up a UDP relay through this proxy, which must speak SOCKS5.
+    ///
+    /// Returns the relay and `target` resolved according to `resolution`.
+    pub(crate) async fn associate(
+        &self,
+        resolver: &Resolver,
+        target: &TargetAddr,
+    ) -> io::Result<(Socks5Datagram, TargetAddr)> {
+        if self.protocol != ProxyProtocol::Socks5 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "UDP forwarding needs a SOCKS5 proxy",
+            ));
+        }
+        self.require_plain("UDP forwarding")?;
+
+        let target = resolve_target(target, self.resolution).await?;
+        let proxy = self.proxy_addrs(resolver).await?;
+        let options = self.socks5_options(Resolution::Remote);
+        let local = if proxy[0].is_ipv6() {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        };
+        let datagram =
+            Socks5Datagram::bind_with_options(proxy.as_slice(), local, &self.auth, &options)
+                .await?;
+        Ok((datagram, target))
+    }
+
+    /// Asks this proxy, which must speak SOCKS5, to listen for a connection
+    /// from `peer`.
+    pub(crate) async fn bind(
+        &self,
+        resolver: &Resolver,
+        peer: &TargetAddr,
+    ) -> io::Result<Socks5Listener> {
+        if self.protocol != ProxyProtocol::Socks5 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "reverse forwarding needs a SOCKS5 proxy",
+            ));
+        }
+        self.require_plain("reverse forwarding")?;
+
+        let proxy = self.proxy_addrs(resolver).await?;
+        let options = self.socks5_options(self.resolution);
+        let listener =
+            Socks5Listener::bind_with_options(proxy.as_slice(), peer.clone(), &self.auth, &options)
+                .await?;
+        Ok(listener)
+    }
+
+    /// Returns the addresses of this proxy, never empty.
+    async fn proxy_addrs(&self, resolver: &Resolver) -> io::Result<Vec<SocketAddr>> {
+        match &self.addr {
+            TargetAddr::Ip(addr) => Ok(vec![*addr]),
+            TargetAddr::Domain(host, port) => resolver.lookup(host, *port).await,
+        }
+    }
+
+    fn socks5_options(&self, resolution: Resolution) -> ConnectOptions {
+        ConnectOptions {
+            resolution,
+            timeouts: self.timeouts.clone(),
+            auth_policy: self.auth_policy,
+        }
+    }
+
+    /// Connects to this proxy at one of `proxy` and authenticates with
+    /// `auth` over SOCKS5.
+    async fn handshake(
+        &self,
+        proxy: &[SocketAddr],
+        auth: &Authentication,
+    ) -> io::Result<Socks5Session<ProxyStream>> {
+        let stream = match self.timeouts.connect {
+            Some(limit) => match timeout(limit, self.open_stream(proxy)).await {
+                Ok(stream) => stream?,
+                Err(_) => {
+                    return Err(Socks5Error::Timeout {
+                        phase: "connect",
+                        limit,
+                    }
+                    .into())
+                }
+            },
+            None => self.open_stream(proxy).await?,
+        };
+        let options = self.socks5_options(self.resolution);
+        Ok(Socks5Session::handshake(stream, auth, &options).await?)
+    }
+
+    /// Connects to this proxy at one of `proxy`, with TLS if configured.
+    async fn open_stream(&self, proxy: &[SocketAddr]) -> io::Result<ProxyStream> {
+        let socket = happy_eyeballs::connect(proxy).await?;
+        #[cfg(feature = "tls")]
+        {
+            if let Some(tls) = &self.tls {
+                let host = match &self.addr {
+                    TargetAddr::Domain(host, _) => host.clone(),
+                    TargetAddr::Ip(addr) => addr.ip().to_string(),
+                };
+                let stream = tls.connect(socket, &host).await?;
+                return Ok(ProxyStream::Tls(Box::new(stream)));
+            }
+        }
+        Ok(ProxyStream::Tcp(socket))
+    }
+
+    /// Fails if this proxy is reached over TLS, which `what` does not
+    /// support.
+    #[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+    fn require_plain(&self, what: &str) -> io::Result<()> {
+        #[cfg(feature = "tls")]
+        {
+            if self.tls.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} over TLS is not supported", what),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<TcpStream> for UpstreamStream {
+    fn from(socket: TcpStream) -> Self {
+        UpstreamStream {
+            socket: ProxyStream::Tcp(socket),
+            buffered: vec![],
+            auth_method: None,
+            latency: None,
+        }
+    }
+}
+
+impl UpstreamStream {
+    /// Returns the auth method a SOCKS5 proxy selected, `None` with other
+    /// protocols.
+    pub fn auth_method(&self) -> Option<u8> {
+        self.auth_method
+    }
+
+    /// Returns the time from the start of the connect to the proxy until its
+    /// reply, `None` if the tunnel was established over a warm session.
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+}
+
+/// A failure caused by the target rather than the proxy.
+#[derive(Debug)]
+struct TargetError(io::Error);
+
+impl fmt::Display for TargetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl error::Error for TargetError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+/// Returns whether `error` was caused by the target, such as a hostname
+/// that does not resolve, rather than by the proxy.
+pub(crate) fn is_target_error(error: &io::Error) -> bool {
+    error
+        .get_ref()
+        .is_some_and(|e| e.downcast_ref::<TargetError>().is_some())
+}
+
+/// Marks `error` as caused by the target, see `is_target_error`.
+pub(crate) fn target_error(error: io::Error) -> io::Error {
+    io::Error::new(error.kind(), TargetError(error))
+}
+
+/// Resolves `target` locally as `resolution` asks, marking failures as
+/// target errors.
+async fn resolve_target(target: &TargetAddr, resolution: Resolution) -> io::Result<TargetAddr> {
+    target.resolve(resolution).await.map_err(target_error)
+}
+
+/// Relays data between a client and an established upstream tunnel.
+pub(crate) async fn forward(mut client: TcpStream, upstream: UpstreamStream) -> io::Result<()> {
+    let UpstreamStream {
+        socket: mut proxy_stream,
+        buffered,
+        ..
+    } = upstream;
+
+    if !buffered.is_empty() {
+        client.write_all(&buffered).await?;
+    }
+
+    let (client_read, client_write) = client.split();
+    match &mut proxy_stream {
+        ProxyStream::Tcp(socket) => {
+            let (proxy_read, proxy_write) = socket.split();
+            try_join!(
+                pipe(client_read, proxy_write),
+                pipe(proxy_read, client_write),
+            )?;
+        }
+        #[cfg(feature = "tls")]
+        ProxyStream::Tls(stream) => {
+            let (proxy_read, proxy_write) = tokio::io::split(stream);
+            try_join!(
+                pipe(client_read, proxy_write),
+                pipe(proxy_read, client_write),
+            )?;
+        }
+    }
+
+    println!("[client] joined task done");
+
+    shutdown_both(&client, proxy_stream.tcp());
+
+    Ok(())
+}
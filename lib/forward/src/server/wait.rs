@@ -0,0 +1,207 @@
+use super::pool::UpstreamPool;
+use super::retry::RetryPolicy;
+use super::upstream::UpstreamStream;
+use crate::auth::Authentication;
+use crate::target_addr::TargetAddr;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::time::{delay_for, timeout};
+
+/// Hold accepted clients while no upstream proxy can be reached.
+///
+/// Errors the retry policy would not retry, such as failed authentication,
+/// close the client at once. The default policy decides without `retry`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WaitForUpstream {
+    /// Clients waiting at the same time. Further clients are closed at once.
+    pub max_queued: usize,
+
+    /// How long a client waits before it is closed.
+    pub max_wait: Duration,
+
+    /// Time between two connect attempts of a waiting client.
+    pub retry_interval: Duration,
+}
+
+impl Default for WaitForUpstream {
+    fn default() -> Self {
+        WaitForUpstream {
+            max_queued: 64,
+            max_wait: Duration::from_secs(5),
+            retry_interval: Duration::from_millis(500),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct WaitQueue {
+    config: WaitForUpstream,
+
+    /// Decides which errors are worth waiting out.
+    retry: RetryPolicy,
+    slots: Semaphore,
+}
+
+impl WaitQueue {
+    pub fn new(config: WaitForUpstream, retry: RetryPolicy) -> WaitQueue {
+        WaitQueue {
+            slots: Semaphore::new(config.max_queued),
+            config,
+            retry,
+        }
+    }
+
+    /// Keeps trying to connect to `target` after `error` until it succeeds,
+    /// the wait time is over, the queue is full or an error is not worth
+    /// retrying.
+    ///
+    /// Nothing is read from the client meanwhile, its socket just stays open.
+    pub async fn wait(
+        &self,
+        pool: &UpstreamPool,
+        target: &TargetAddr,
+        isolation: Option<&Authentication>,
+        client: SocketAddr,
+        error: io::Error,
+    ) -> io::Result<UpstreamStream> {
+        if !self.retry.is_retryable(&error) {
+            return Err(error);
+        }
+        let _slot = match self.slots.try_acquire() {
+            Ok(slot) => slot,
+            Err(_) => {
+                eprintln!(
+                    "Closing {}: upstream unavailable ({}) and wait queue full",
+                    client, error
+                );
+                return Err(error);
+            }
+        };
+
+        println!("Holding {} until the upstream recovers: {}", client, error);
+        let deadline = Instant::now() + self.config.max_wait;
+        let mut last_error = error;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            delay_for(self.config.retry_interval.min(deadline - now)).await;
+
+            let left = deadline.saturating_duration_since(Instant::now());
+            match timeout(left, pool.connect(target, isolation)).await {
+                Ok(Ok(stream)) => {
+                    println!("Upstream recovered, serving {}", client);
+                    return Ok(stream);
+                }
+                Ok(Err(error)) if !self.retry.is_retryable(&error) => {
+                    eprintln!("Closing {}: {}", client, error);
+                    return Err(error);
+                }
+                Ok(Err(error)) => last_error = error,
+                Err(_) => break,
+            }
+        }
+
+        eprintln!(
+            "Closing {}: upstream still unavailable after {:?}: {}",
+            client, self.config.max_wait, last_error
+        );
+        Err(last_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socks5::Socks5Error;
+    use crate::target_addr::ToTargetAddr;
+    use tokio::sync::broadcast;
+
+    fn error() -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionRefused, "refused")
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_wait() {
+        // Nothing listens on port 1, so every attempt is refused.
+        let pool = UpstreamPool::new(
+            vec!["socks5h://127.0.0.1:1".parse().unwrap()],
+            Default::default(),
+            Default::default(),
+            None,
+            Default::default(),
+            broadcast::channel(1).0,
+        );
+        let queue = WaitQueue::new(
+            WaitForUpstream {
+                max_queued: 1,
+                max_wait: Duration::from_millis(50),
+                retry_interval: Duration::from_millis(10),
+            },
+            RetryPolicy::default(),
+        );
+        let target = ("127.0.0.1", 80).to_target_addr().unwrap();
+        let client = "127.0.0.1:5000".parse().unwrap();
+
+        let started = Instant::now();
+        assert!(queue
+            .wait(&pool, &target, None, client, error())
+            .await
+            .is_err());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn full_queue_closes_at_once() {
+        let pool = UpstreamPool::new(
+            vec![],
+            Default::default(),
+            Default::default(),
+            None,
+            Default::default(),
+            broadcast::channel(1).0,
+        );
+        let queue = WaitQueue::new(
+            WaitForUpstream {
+                max_queued: 0,
+                max_wait: Duration::from_secs(60),
+                retry_interval: Duration::from_secs(1),
+            },
+            RetryPolicy::default(),
+        );
+        let target = ("127.0.0.1", 80).to_target_addr().unwrap();
+        let client = "127.0.0.1:5000".parse().unwrap();
+
+        let error = queue
+            .wait(&pool, &target, None, client, error())
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn permanent_errors_are_not_waited_out() {
+        let pool = UpstreamPool::new(
+            vec![],
+            Default::default(),
+            Default::default(),
+            None,
+            Default::default(),
+            broadcast::channel(1).0,
+        );
+        let queue = WaitQueue::new(WaitForUpstream::default(), RetryPolicy::default());
+        let target = ("127.0.0.1", 80).to_target_addr().unwrap();
+        let client = "127.0.0.1:5000".parse().unwrap();
+
+        let started = Instant::now();
+        let denied = Socks5Error::Reply(2).into();
+        assert!(queue
+            .wait(&pool, &target, None, client, denied)
+            .await
+            .is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,296 @@
+use super::pool::UpstreamPool;
+use super::stopping;
+use super::upstream::ProxyStream;
+use crate::socks5::Socks5Session;
+use futures::future::FutureExt;
+use futures::select;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::time::delay_for;
+
+/// Time before opening a session again after the proxy failed.
+const REOPEN_DELAY: Duration = Duration::from_secs(5);
+
+/// Sessions kept open to each SOCKS5 proxy, already through authentication,
+/// so that a new client only waits for its CONNECT.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WarmPoolConfig {
+    /// Sessions kept ready per proxy.
+    pub size: usize,
+
+    /// Age after which an unused session is closed, before the proxy closes
+    /// it as idle.
+    pub max_age: Duration,
+}
+
+impl Default for WarmPoolConfig {
+    fn default() -> Self {
+        WarmPoolConfig {
+            size: 4,
+            max_age: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct WarmSession {
+    opened: Instant,
+    session: Socks5Session<ProxyStream>,
+}
+
+/// The ready sessions of every upstream proxy.
+#[derive(Debug)]
+pub(crate) struct WarmPool {
+    config: WarmPoolConfig,
+    sessions: Vec<Mutex<VecDeque<WarmSession>>>,
+
+    /// Wakes the refill tasks when a session was taken.
+    taken_tx: watch::Sender<()>,
+    taken_rx: watch::Receiver<()>,
+}
+
+impl WarmPool {
+    pub fn new(config: WarmPoolConfig, upstreams: usize) -> WarmPool {
+        let (taken_tx, taken_rx) = watch::channel(());
+        WarmPool {
+            config,
+            sessions: (0..upstreams).map(|_| Mutex::default()).collect(),
+            taken_tx,
+            taken_rx,
+        }
+    }
+
+    /// Takes the oldest session to the upstream at `index` that is not too
+    /// old.
+    pub fn take(&self, index: usize) -> Option<Socks5Session<ProxyStream>> {
+        let session = {
+            let mut sessions = self.sessions[index].lock().unwrap();
+            self.evict(&mut sessions);
+            sessions.pop_front()
+        };
+        if session.is_some() {
+            // `taken_rx` is kept, so this cannot fail.
+            let _ = self.taken_tx.broadcast(());
+        }
+        session.map(|s| s.session)
+    }
+
+    /// Returns how many sessions to the upstream at `index` are missing.
+    fn missing(&self, index: usize) -> usize {
+        let mut sessions = self.sessions[index].lock().unwrap();
+        self.evict(&mut sessions);
+        self.config.size.saturating_sub(sessions.len())
+    }
+
+    fn put(&self, index: usize, session: Socks5Session<ProxyStream>) {
+        self.sessions[index].lock().unwrap().push_back(WarmSession {
+            opened: Instant::now(),
+            session,
+        });
+    }
+
+    fn evict(&self, sessions: &mut VecDeque<WarmSession>) {
+        while let Some(oldest) = sessions.front() {
+            if oldest.opened.elapsed() < self.config.max_age {
+                break;
+            }
+            sessions.pop_front();
+        }
+    }
+}
+
+/// Keeps the warm sessions to the upstream at `index` topped up until the
+/// server stops.
+pub(crate) async fn run_warm_pool(
+    pool: Arc<UpstreamPool>,
+    index: usize,
+    mut state_rx: watch::Receiver<u8>,
+) {
+    let warm = match pool.warm() {
+        Some(warm) => warm,
+        None => return,
+    };
+    let mut taken_rx = warm.taken_rx.clone();
+    let upstream = pool.upstream(index);
+    loop {
+        let mut wait = warm.config.max_age / 2;
+        if warm.missing(index) > 0 {
+            select! {
+                opened = upstream.open_session(pool.resolver()).fuse() => match opened {
+                    Ok(session) => {
+                        warm.put(index, session);
+                        continue;
+                    }
+                    Err(error) => {
+                        eprintln!(
+                            "Warm session to {:?} failed: {}, retry in {:?}",
+                            upstream.addr, error, REOPEN_DELAY
+                        );
+                        wait = REOPEN_DELAY;
+                    }
+                },
+                _ = stopping(&mut state_rx).fuse() => break,
+            }
+        }
+
+        select! {
+            _ = taken_rx.recv().fuse() => {},
+            _ = delay_for(wait).fuse() => {},
+            _ = stopping(&mut state_rx).fuse() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Authentication;
+    use crate::resolver::Resolver;
+    use crate::socks5::ConnectOptions;
+    use crate::target_addr::ToTargetAddr;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::broadcast;
+
+    async fn session() -> Socks5Session<ProxyStream> {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            socket.read_exact(&mut greeting).await.unwrap();
+            socket.write_all(&[5, 0]).await.unwrap();
+            // Keep the connection open.
+            let _ = socket.read_u8().await;
+        });
+        let socket = TcpStream::connect(proxy).await.unwrap();
+        Socks5Session::handshake(
+            ProxyStream::Tcp(socket),
+            &Authentication::None,
+            &ConnectOptions::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn old_sessions_are_evicted() {
+        let warm = WarmPool::new(
+            WarmPoolConfig {
+                size: 2,
+                max_age: Duration::from_millis(50),
+            },
+            1,
+        );
+        assert_eq!(warm.missing(0), 2);
+
+        warm.put(0, session().await);
+        assert_eq!(warm.missing(0), 1);
+        assert!(warm.take(0).is_some());
+        assert!(warm.take(0).is_none());
+
+        warm.put(0, session().await);
+        delay_for(Duration::from_millis(60)).await;
+        assert_eq!(warm.missing(0), 2);
+        assert!(warm.take(0).is_none());
+    }
+
+    /// Runs a SOCKS5 proxy that grants every CONNECT to an IP address, or
+    /// closes its first connection after the handshake if `stale`. Returns
+    /// its address and the number of connections it accepted.
+    async fn proxy(stale: bool) -> (SocketAddr, Arc<AtomicUsize>) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let first = counter.fetch_add(1, Ordering::SeqCst) == 0;
+                tokio::spawn(async move {
+                    let mut greeting = [0; 3];
+                    socket.read_exact(&mut greeting).await.unwrap();
+                    socket.write_all(&[5, 0]).await.unwrap();
+                    if stale && first {
+                        return;
+                    }
+                    let mut request = [0; 4];
+                    socket.read_exact(&mut request).await.unwrap();
+                    let mut addr = match request[3] {
+                        1 => vec![0; 6],
+                        4 => vec![0; 18],
+                        _ => panic!("target not resolved"),
+                    };
+                    socket.read_exact(&mut addr).await.unwrap();
+                    socket
+                        .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80])
+                        .await
+                        .unwrap();
+                    let _ = socket.read_u8().await;
+                });
+            }
+        });
+        (addr, accepted)
+    }
+
+    async fn warm_pool(scheme: &str, proxy: SocketAddr) -> UpstreamPool {
+        let pool = UpstreamPool::new(
+            vec![format!("{}://{}", scheme, proxy).parse().unwrap()],
+            Default::default(),
+            Default::default(),
+            Some(WarmPoolConfig::default()),
+            Resolver::default(),
+            broadcast::channel(1).0,
+        );
+        let session = pool
+            .upstream(0)
+            .open_session(pool.resolver())
+            .await
+            .unwrap();
+        pool.warm().unwrap().put(0, session);
+        pool
+    }
+
+    #[tokio::test]
+    async fn warm_session_is_used() {
+        let (proxy, accepted) = proxy(false).await;
+        let pool = warm_pool("socks5h", proxy).await;
+        let target = ("127.0.0.1", 80).to_target_addr().unwrap();
+
+        let stream = pool.connect(&target, None).await.unwrap();
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        // A warm session skips the connect and handshake, so it is no sample.
+        assert_eq!(stream.latency(), None);
+        assert_eq!(pool.status()[0].latency, None);
+
+        let stream = pool.connect(&target, None).await.unwrap();
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+        assert!(stream.latency().is_some());
+        assert_eq!(pool.status()[0].latency, stream.latency());
+    }
+
+    #[tokio::test]
+    async fn warm_session_resolves_target_locally() {
+        let (proxy, accepted) = proxy(false).await;
+        let pool = warm_pool("socks5", proxy).await;
+        let target = ("localhost", 80).to_target_addr().unwrap();
+
+        assert!(pool.connect(&target, None).await.is_ok());
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn stale_warm_session_falls_back() {
+        let (proxy, accepted) = proxy(true).await;
+        let pool = warm_pool("socks5h", proxy).await;
+        let target = ("127.0.0.1", 80).to_target_addr().unwrap();
+
+        assert!(pool.connect(&target, None).await.is_ok());
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.status()[0].consecutive_failures, 0);
+    }
+}
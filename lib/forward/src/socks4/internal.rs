@@ -0,0 +1,35 @@
+use super::Socks4Stream;
+use crate::server::{pipe, shutdown_both};
+use crate::target_addr::ToTargetAddr;
+use futures::try_join;
+use std::io;
+use tokio::net::TcpStream;
+use tokio::net::ToSocketAddrs;
+
+pub async fn forward_tcp_to_socks4(
+    mut client: TcpStream,
+    proxy: impl ToSocketAddrs,
+    user_id: &str,
+    target: impl ToTargetAddr,
+) -> io::Result<()> {
+    println!("Accepted connection from {:?}", client.peer_addr().unwrap());
+
+    let (client_read, client_write) = client.split();
+
+    let mut proxy_stream = Socks4Stream::connect(proxy, target, user_id)
+        .await?
+        .into_inner();
+
+    let (proxy_read, proxy_write) = proxy_stream.split();
+
+    try_join!(
+        pipe(client_read, proxy_write),
+        pipe(proxy_read, client_write),
+    )?;
+
+    println!("[client] joined task done");
+
+    shutdown_both(&client, &proxy_stream);
+
+    Ok(())
+}
@@ -0,0 +1,211 @@
+mod internal;
+pub use internal::forward_tcp_to_socks4;
+
+use super::happy_eyeballs;
+use super::target_addr::{TargetAddr, ToTargetAddr};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::net::ToSocketAddrs;
+
+const MAX_REQUEST_LEN: usize = 9 + 255 + 256;
+
+/// Why a SOCKS4 request failed.
+///
+/// It is the inner error of the `io::Error` that `Socks4Stream::connect`
+/// returns, so it can be found with `io::Error::get_ref` and `downcast_ref`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Socks4Error {
+    /// The proxy rejected the request with this reply code, usually 91 to 93.
+    Reply(u8),
+}
+
+impl Socks4Error {
+    /// Returns the `io::ErrorKind` this error converts to.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Socks4Error::Reply(93) => io::ErrorKind::PermissionDenied,
+            Socks4Error::Reply(_) => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for Socks4Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Socks4Error::Reply(code) => f.write_str(match code {
+                91 => "request rejected or failed",
+                92 => "request rejected: proxy cannot connect to identd on the client",
+                93 => "request rejected: identd reported a different user id",
+                _ => "unknown error",
+            }),
+        }
+    }
+}
+
+impl Error for Socks4Error {}
+
+impl From<Socks4Error> for io::Error {
+    fn from(error: Socks4Error) -> Self {
+        io::Error::new(error.kind(), error)
+    }
+}
+
+pub struct Socks4Stream {
+    socket: TcpStream,
+    proxy_addr: SocketAddrV4,
+}
+
+impl Socks4Stream {
+    /// Connects to a target server through a SOCKS4 proxy.
+    ///
+    /// IPv4 targets are requested with SOCKS4. Domain targets are requested
+    /// with the SOCKS4a extension, leaving DNS lookup to the proxy. IPv6
+    /// targets can not be expressed in either protocol.
+    pub async fn connect<T, U>(proxy: T, target: U, user_id: &str) -> io::Result<Socks4Stream>
+    where
+        T: ToSocketAddrs,
+        U: ToTargetAddr,
+    {
+        let target = target.to_target_addr()?;
+        let mut packet = [0; MAX_REQUEST_LEN];
+        let len = write_request(&mut packet, 1, &target, user_id)?;
+
+        let mut socket = happy_eyeballs::connect(proxy).await?;
+        socket.write_all(&packet[..len]).await?;
+
+        let proxy_addr = read_response(&mut socket).await?;
+
+        Ok(Socks4Stream { socket, proxy_addr })
+    }
+
+    /// Returns the proxy-side address of the connection between the proxy and
+    /// target server.
+    pub fn proxy_addr(&self) -> SocketAddrV4 {
+        self.proxy_addr
+    }
+
+    /// Returns a shared reference to the inner `TcpStream`.
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    /// Returns a mutable reference to the inner `TcpStream`.
+    pub fn get_mut(&mut self) -> &mut TcpStream {
+        &mut self.socket
+    }
+
+    /// Consumes the `Socks4Stream`, returning the inner `TcpStream`.
+    pub fn into_inner(self) -> TcpStream {
+        self.socket
+    }
+}
+
+fn write_request(
+    packet: &mut [u8],
+    command: u8,
+    target: &TargetAddr,
+    user_id: &str,
+) -> io::Result<usize> {
+    if user_id.len() > 255 || user_id.as_bytes().contains(&0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid user id",
+        ));
+    }
+
+    let (ip, port, domain) = match target {
+        TargetAddr::Ip(SocketAddr::V4(addr)) => (*addr.ip(), addr.port(), None),
+        TargetAddr::Ip(SocketAddr::V6(_)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "SOCKS4 does not support IPv6 targets",
+            ))
+        }
+        TargetAddr::Domain(domain, port) => {
+            if domain.len() > 255 || domain.as_bytes().contains(&0) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "invalid domain name",
+                ));
+            }
+            // 0.0.0.x with x != 0 marks a SOCKS4a request
+            (Ipv4Addr::new(0, 0, 0, 1), *port, Some(domain))
+        }
+    };
+
+    packet[0] = 4; // protocol version
+    packet[1] = command; // command
+    packet[2..4].copy_from_slice(&port.to_be_bytes());
+    packet[4..8].copy_from_slice(&ip.octets());
+    let mut len = 8;
+    packet[len..len + user_id.len()].copy_from_slice(user_id.as_bytes());
+    len += user_id.len();
+    packet[len] = 0;
+    len += 1;
+    if let Some(domain) = domain {
+        packet[len..len + domain.len()].copy_from_slice(domain.as_bytes());
+        len += domain.len();
+        packet[len] = 0;
+        len += 1;
+    }
+
+    Ok(len)
+}
+
+async fn read_response(socket: &mut TcpStream) -> io::Result<SocketAddrV4> {
+    let mut buf = [0; 8];
+    socket.read_exact(&mut buf).await?;
+
+    if buf[0] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid response version",
+        ));
+    }
+
+    if buf[1] != 90 {
+        return Err(Socks4Error::Reply(buf[1]).into());
+    }
+
+    let port = u16::from_be_bytes([buf[2], buf[3]]);
+    let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+    Ok(SocketAddrV4::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socks4_request() {
+        let mut packet = [0; MAX_REQUEST_LEN];
+        let target = ("127.0.0.1", 80).to_target_addr().unwrap();
+        let len = write_request(&mut packet, 1, &target, "usr").unwrap();
+        assert_eq!(
+            &packet[..len],
+            &[4, 1, 0, 80, 127, 0, 0, 1, b'u', b's', b'r', 0]
+        );
+    }
+
+    #[test]
+    fn socks4a_request() {
+        let mut packet = [0; MAX_REQUEST_LEN];
+        let target = ("a.io", 443).to_target_addr().unwrap();
+        let len = write_request(&mut packet, 1, &target, "").unwrap();
+        assert_eq!(
+            &packet[..len],
+            &[4, 1, 1, 187, 0, 0, 0, 1, 0, b'a', b'.', b'i', b'o', 0]
+        );
+    }
+
+    #[test]
+    fn socks4_rejects_ipv6() {
+        let mut packet = [0; MAX_REQUEST_LEN];
+        let target = ("::1", 80).to_target_addr().unwrap();
+        assert!(write_request(&mut packet, 1, &target, "").is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use super::Socks5Error;
 use crate::target_addr::TargetAddr;
 use byteorder::{BigEndian, WriteBytesExt};
 use std::io;
@@ -5,9 +6,9 @@ use std::io::Write;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use tokio::io::AsyncRead;
 use tokio::io::AsyncWrite;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-const MAX_ADDR_LEN: usize = 260;
+pub const MAX_ADDR_LEN: usize = 260;
 
 pub async fn read_addr<R: AsyncRead + Unpin>(socket: &mut R) -> io::Result<TargetAddr> {
     match socket.read_u8().await? {
@@ -55,7 +56,7 @@ pub async fn write_addr<R: AsyncWrite + Unpin>(
     Ok(())
 }
 
-fn write_addr_to_packet(mut packet: &mut [u8], target: &TargetAddr) -> io::Result<usize> {
+pub fn write_addr_to_packet(mut packet: &mut [u8], target: &TargetAddr) -> io::Result<usize> {
     let start_len = packet.len();
     match target {
         TargetAddr::Ip(SocketAddr::V4(addr)) => {
@@ -85,52 +86,22 @@ fn write_addr_to_packet(mut packet: &mut [u8], target: &TargetAddr) -> io::Resul
     Ok(start_len - packet.len())
 }
 
-pub async fn read_response<R: AsyncRead + Unpin>(socket: &mut R) -> io::Result<TargetAddr> {
-    let mut socket = BufReader::with_capacity(MAX_ADDR_LEN + 3, socket);
-    if socket.read_u8().await? != 5 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "invalid response version",
-        ));
+pub async fn read_response<R: AsyncRead + Unpin>(
+    socket: &mut R,
+) -> Result<TargetAddr, Socks5Error> {
+    // Read unbuffered: whatever follows the reply belongs to the caller.
+    let mut header = [0; 3];
+    socket.read_exact(&mut header).await?;
+    if header[0] != 5 {
+        return Err(Socks5Error::Protocol("invalid response version"));
     }
-    match socket.read_u8().await? {
+    match header[1] {
         0 => {}
-        1 => {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "general SOCKS server failure",
-            ))
-        }
-        2 => {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "connection not allowed by ruleset",
-            ))
-        }
-        3 => return Err(io::Error::new(io::ErrorKind::Other, "network unreachable")),
-        4 => return Err(io::Error::new(io::ErrorKind::Other, "host unreachable")),
-        5 => return Err(io::Error::new(io::ErrorKind::Other, "connection refused")),
-        6 => return Err(io::Error::new(io::ErrorKind::Other, "TTL expired")),
-        7 => {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "command not supported",
-            ))
-        }
-        8 => {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "address kind not supported",
-            ))
-        }
-        _ => return Err(io::Error::new(io::ErrorKind::Other, "unknown error")),
+        code => return Err(Socks5Error::Reply(code)),
     }
-    if socket.read_u8().await? != 0 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "invalid reserved byte",
-        ));
+    if header[2] != 0 {
+        return Err(Socks5Error::Protocol("invalid reserved byte"));
     }
 
-    read_addr(&mut socket).await
+    Ok(read_addr(socket).await?)
 }
@@ -1,22 +1,17 @@
+use super::Socks5Error;
 use std::io;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-pub async fn password_authentication<S: AsyncRead + AsyncWrite + Unpin>(
+pub async fn password_authentication<S: AsyncRead + AsyncWrite + Unpin + ?Sized>(
     socket: &mut S,
     username: &str,
     password: &str,
-) -> io::Result<()> {
+) -> Result<(), Socks5Error> {
     if username.len() < 1 || username.len() > 255 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "invalid username",
-        ));
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid username").into());
     };
     if password.len() < 1 || password.len() > 255 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "invalid password",
-        ));
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid password").into());
     }
 
     let mut packet = [0; 515];
@@ -31,16 +26,10 @@ pub async fn password_authentication<S: AsyncRead + AsyncWrite + Unpin>(
     let mut buf = [0; 2];
     socket.read_exact(&mut buf).await?;
     if buf[0] != 1 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "invalid response version",
-        ));
+        return Err(Socks5Error::Protocol("invalid response version"));
     }
     if buf[1] != 0 {
-        return Err(io::Error::new(
-            io::ErrorKind::PermissionDenied,
-            "password authentication failed",
-        ));
+        return Err(Socks5Error::AuthFailed);
     }
 
     Ok(())
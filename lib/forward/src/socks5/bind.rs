@@ -0,0 +1,151 @@
+use super::addr::read_response;
+use super::{ConnectOptions, Socks5Error, Socks5Stream};
+use crate::auth::Authentication;
+use crate::target_addr::{TargetAddr, ToTargetAddr};
+use tokio::net::ToSocketAddrs;
+
+/// A port opened on a SOCKS5 proxy with BIND, waiting for one incoming
+/// connection.
+pub struct Socks5Listener {
+    inner: Socks5Stream,
+}
+
+impl Socks5Listener {
+    /// Asks the proxy to listen for a connection from `peer`.
+    ///
+    /// Most proxies only accept a connection coming from the address of
+    /// `peer`, usually the server of an active-mode protocol.
+    pub async fn bind<T, U>(
+        proxy: T,
+        peer: U,
+        auth: &Authentication,
+    ) -> Result<Socks5Listener, Socks5Error>
+    where
+        T: ToSocketAddrs,
+        U: ToTargetAddr,
+    {
+        Self::bind_with_options(proxy, peer, auth, &ConnectOptions::default()).await
+    }
+
+    /// Like `bind`, with the given options. The reply timeout only applies to
+    /// the first reply, `accept` waits for the peer as long as it takes.
+    ///
+    /// If the proxy replies with an unspecified address such as `0.0.0.0`,
+    /// the address the proxy was reached at is used instead.
+    pub async fn bind_with_options<T, U>(
+        proxy: T,
+        peer: U,
+        auth: &Authentication,
+        options: &ConnectOptions,
+    ) -> Result<Socks5Listener, Socks5Error>
+    where
+        T: ToSocketAddrs,
+        U: ToTargetAddr,
+    {
+        let mut inner =
+            Socks5Stream::connect_raw_with_options(2, proxy, peer, auth, options).await?;
+        if let TargetAddr::Ip(addr) = &mut inner.proxy_addr {
+            if addr.ip().is_unspecified() {
+                addr.set_ip(inner.socket.peer_addr()?.ip());
+            }
+        }
+        Ok(Socks5Listener { inner })
+    }
+
+    /// Returns the address the proxy listens on, which is to be sent to the
+    /// peer.
+    pub fn proxy_addr(&self) -> &TargetAddr {
+        &self.inner.proxy_addr
+    }
+
+    /// Returns the id of the auth method the proxy selected.
+    pub fn auth_method(&self) -> u8 {
+        self.inner.auth_method
+    }
+
+    /// Waits for the peer to connect, returning the stream to it and its
+    /// address.
+    pub async fn accept(mut self) -> Result<(Socks5Stream, TargetAddr), Socks5Error> {
+        let peer_addr = read_response(&mut self.inner.socket).await?;
+        self.inner.proxy_addr = peer_addr.clone();
+        Ok((self.inner, peer_addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn bind_reads_both_replies() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            socket.read_exact(&mut greeting).await.unwrap();
+            socket.write_all(&[5, 0]).await.unwrap();
+
+            let mut request = [0; 10];
+            socket.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[1], 2);
+            socket
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x1f, 0x90])
+                .await
+                .unwrap();
+            socket
+                .write_all(&[5, 0, 0, 1, 10, 0, 0, 2, 0x30, 0x39])
+                .await
+                .unwrap();
+            socket.write_all(b"hi").await.unwrap();
+        });
+
+        let listener = Socks5Listener::bind(proxy, ("10.0.0.2", 0), &Authentication::None)
+            .await
+            .unwrap();
+        assert_eq!(
+            listener.proxy_addr(),
+            &("127.0.0.1", 8080).to_target_addr().unwrap()
+        );
+
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(error) => panic!("accept failed: {}", error),
+        };
+        assert_eq!(peer, ("10.0.0.2", 12345).to_target_addr().unwrap());
+        assert_eq!(stream.proxy_addr(), &peer);
+
+        let mut data = [0; 2];
+        stream.get_mut().read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"hi");
+    }
+
+    #[tokio::test]
+    async fn unspecified_bound_addr_is_replaced() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            socket.read_exact(&mut greeting).await.unwrap();
+            socket.write_all(&[5, 0]).await.unwrap();
+
+            let mut request = [0; 10];
+            socket.read_exact(&mut request).await.unwrap();
+            socket
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0x1f, 0x90])
+                .await
+                .unwrap();
+        });
+
+        let listener = Socks5Listener::bind(proxy, ("10.0.0.2", 0), &Authentication::None)
+            .await
+            .unwrap();
+        assert_eq!(
+            listener.proxy_addr(),
+            &("127.0.0.1", 8080).to_target_addr().unwrap()
+        );
+    }
+}
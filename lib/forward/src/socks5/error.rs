@@ -0,0 +1,143 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// Why a SOCKS5 handshake failed.
+///
+/// It converts into an `io::Error` of the matching kind and back, so it can
+/// be recovered with `Socks5Error::from` after passing through code that
+/// only knows `io::Error`.
+#[derive(Debug)]
+pub enum Socks5Error {
+    /// The proxy answered the request with this reply code, which is never 0.
+    Reply(u8),
+
+    /// The proxy accepted none of the offered auth methods.
+    NoAcceptableAuthMethod,
+
+    /// The proxy rejected the username and password.
+    AuthFailed,
+
+    /// The proxy selected no authentication although it was not offered,
+    /// see `AuthPolicy::RequireConfigured`.
+    AuthDowngraded,
+
+    /// The proxy sent something that is not valid SOCKS5.
+    Protocol(&'static str),
+
+    /// A phase of the handshake took longer than its timeout.
+    Timeout {
+        phase: &'static str,
+        limit: Duration,
+    },
+
+    Io(io::Error),
+}
+
+impl Socks5Error {
+    /// Returns the reply code sent by the proxy, if that is what failed.
+    pub fn reply_code(&self) -> Option<u8> {
+        match self {
+            Socks5Error::Reply(code) => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// Returns the `io::ErrorKind` this error converts to.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Socks5Error::Reply(2) => io::ErrorKind::PermissionDenied,
+            Socks5Error::Reply(5) => io::ErrorKind::ConnectionRefused,
+            Socks5Error::Reply(6) => io::ErrorKind::TimedOut,
+            Socks5Error::Reply(7) | Socks5Error::Reply(8) => io::ErrorKind::InvalidInput,
+            Socks5Error::Reply(_) => io::ErrorKind::Other,
+            Socks5Error::NoAcceptableAuthMethod
+            | Socks5Error::AuthFailed
+            | Socks5Error::AuthDowngraded => io::ErrorKind::PermissionDenied,
+            Socks5Error::Protocol(_) => io::ErrorKind::InvalidData,
+            Socks5Error::Timeout { .. } => io::ErrorKind::TimedOut,
+            Socks5Error::Io(error) => error.kind(),
+        }
+    }
+}
+
+impl fmt::Display for Socks5Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Socks5Error::Reply(code) => f.write_str(match code {
+                1 => "general SOCKS server failure",
+                2 => "connection not allowed by ruleset",
+                3 => "network unreachable",
+                4 => "host unreachable",
+                5 => "connection refused",
+                6 => "TTL expired",
+                7 => "command not supported",
+                8 => "address kind not supported",
+                _ => "unknown error",
+            }),
+            Socks5Error::NoAcceptableAuthMethod => f.write_str("no acceptable auth methods"),
+            Socks5Error::AuthFailed => f.write_str("password authentication failed"),
+            Socks5Error::AuthDowngraded => f.write_str("proxy skipped the required authentication"),
+            Socks5Error::Protocol(msg) => f.write_str(msg),
+            Socks5Error::Timeout { phase, limit } => {
+                write!(f, "SOCKS5 {} timed out after {:?}", phase, limit)
+            }
+            Socks5Error::Io(error) => error.fmt(f),
+        }
+    }
+}
+
+impl Error for Socks5Error {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Socks5Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Socks5Error {
+    fn from(error: io::Error) -> Self {
+        if !error
+            .get_ref()
+            .is_some_and(|inner| inner.is::<Socks5Error>())
+        {
+            return Socks5Error::Io(error);
+        }
+        // Both unwraps are checked above.
+        *error.into_inner().unwrap().downcast().unwrap()
+    }
+}
+
+impl From<Socks5Error> for io::Error {
+    fn from(error: Socks5Error) -> Self {
+        match error {
+            Socks5Error::Io(error) => error,
+            error => io::Error::new(error.kind(), error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_code_survives_io_error() {
+        let error: io::Error = Socks5Error::Reply(2).into();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(error.to_string(), "connection not allowed by ruleset");
+
+        let error = Socks5Error::from(error);
+        assert_eq!(error.reply_code(), Some(2));
+    }
+
+    #[test]
+    fn io_error_is_kept() {
+        let error: io::Error =
+            Socks5Error::Io(io::Error::new(io::ErrorKind::BrokenPipe, "pipe")).into();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(Socks5Error::from(error).reply_code(), None);
+    }
+}
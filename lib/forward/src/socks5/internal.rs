@@ -1,10 +1,9 @@
 use super::Socks5Stream;
 use crate::auth::Authentication;
-use crate::server::pipe;
+use crate::server::{pipe, shutdown_both};
 use crate::target_addr::ToTargetAddr;
 use futures::try_join;
 use std::io;
-use std::net::Shutdown;
 use tokio::net::TcpStream;
 use tokio::net::ToSocketAddrs;
 
@@ -32,15 +31,7 @@ pub async fn forward_tcp_to_socks5(
 
     println!("[client] joined task done");
 
-    // TODO: better error
-    match client.shutdown(Shutdown::Both) {
-        Ok(e) => e,
-        Err(error) => eprintln!("[client] client <=> here shutdown with failue: {}", error),
-    }
-    match proxy_stream.shutdown(Shutdown::Both) {
-        Ok(e) => e,
-        Err(error) => eprintln!("[client] client <=> here shutdown with failue: {}", error),
-    }
+    shutdown_both(&client, &proxy_stream);
 
     Ok(())
 }
@@ -0,0 +1,223 @@
+use super::auth::password_authentication;
+use super::Socks5Error;
+use crate::auth::Authentication;
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The stream an auth method's sub-negotiation runs over.
+pub trait AuthStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AuthStream for T {}
+
+/// A SOCKS5 authentication method that can be offered in the greeting.
+pub trait AuthMethod: Send + Sync {
+    /// The method id sent in the greeting, 0xff is reserved.
+    fn id(&self) -> u8;
+
+    /// Runs the sub-negotiation after the proxy selected this method.
+    fn authenticate<'a>(
+        &'a self,
+        stream: &'a mut dyn AuthStream,
+    ) -> BoxFuture<'a, Result<(), Socks5Error>>;
+}
+
+/// Method 0, no authentication.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NoAuth;
+
+impl AuthMethod for NoAuth {
+    fn id(&self) -> u8 {
+        0
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        _stream: &'a mut dyn AuthStream,
+    ) -> BoxFuture<'a, Result<(), Socks5Error>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Method 2, username and password (RFC 1929).
+#[derive(Debug, Clone, PartialEq)]
+pub struct PasswordAuth {
+    pub username: String,
+    pub password: String,
+}
+
+impl AuthMethod for PasswordAuth {
+    fn id(&self) -> u8 {
+        2
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        stream: &'a mut dyn AuthStream,
+    ) -> BoxFuture<'a, Result<(), Socks5Error>> {
+        Box::pin(password_authentication(
+            stream,
+            &self.username,
+            &self.password,
+        ))
+    }
+}
+
+/// Whether a proxy may skip the configured authentication.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AuthPolicy {
+    /// Offer no authentication after the credentials, and accept it if the
+    /// proxy selects it.
+    #[default]
+    AllowDowngrade,
+
+    /// Offer only the credentials, so a proxy that skips them fails with
+    /// `Socks5Error::AuthDowngraded`.
+    RequireConfigured,
+}
+
+/// The methods offered for `auth`: the credentials if any, then no
+/// authentication unless `policy` requires the credentials.
+pub(crate) fn methods_for(auth: &Authentication, policy: AuthPolicy) -> Vec<Box<dyn AuthMethod>> {
+    match auth {
+        Authentication::Password { username, password } => {
+            let mut methods: Vec<Box<dyn AuthMethod>> = vec![Box::new(PasswordAuth {
+                username: username.clone(),
+                password: password.clone(),
+            })];
+            if policy == AuthPolicy::AllowDowngrade {
+                methods.push(Box::new(NoAuth));
+            }
+            methods
+        }
+        Authentication::None => vec![Box::new(NoAuth)],
+    }
+}
+
+/// Offers `methods` in preference order and runs the sub-negotiation of the
+/// one the proxy selects. Returns the id of the selected method.
+pub(crate) async fn negotiate<S: AuthStream>(
+    stream: &mut S,
+    methods: &[&dyn AuthMethod],
+) -> Result<u8, Socks5Error> {
+    if methods.is_empty() || methods.len() > 255 {
+        return Err(Socks5Error::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "between 1 and 255 auth methods must be offered",
+        )));
+    }
+
+    let mut greeting = Vec::with_capacity(2 + methods.len());
+    greeting.push(5); // protocol version
+    greeting.push(methods.len() as u8);
+    greeting.extend(methods.iter().map(|m| m.id()));
+    stream.write_all(&greeting).await?;
+
+    let mut buf = [0; 2];
+    stream.read_exact(&mut buf).await?;
+    let response_version = buf[0];
+    let selected_method = buf[1];
+
+    if response_version != 5 {
+        return Err(Socks5Error::Protocol("invalid response version"));
+    }
+
+    if selected_method == 0xff {
+        return Err(Socks5Error::NoAcceptableAuthMethod);
+    }
+
+    match methods.iter().find(|m| m.id() == selected_method) {
+        Some(method) => {
+            method.authenticate(stream).await?;
+            Ok(selected_method)
+        }
+        None if selected_method == 0 => Err(Socks5Error::AuthDowngraded),
+        None => Err(Socks5Error::Protocol("unknown auth method")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socks5::{ConnectOptions, Socks5Stream};
+    use tokio::net::TcpListener;
+
+    /// A vendor method that sends one token byte and expects it echoed.
+    struct Token(u8);
+
+    impl AuthMethod for Token {
+        fn id(&self) -> u8 {
+            0x80
+        }
+
+        fn authenticate<'a>(
+            &'a self,
+            stream: &'a mut dyn AuthStream,
+        ) -> BoxFuture<'a, Result<(), Socks5Error>> {
+            Box::pin(async move {
+                stream.write_all(&[self.0]).await?;
+                match stream.read_u8().await? {
+                    echo if echo == self.0 => Ok(()),
+                    _ => Err(Socks5Error::AuthFailed),
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_method_is_negotiated() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 4];
+            socket.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 2, 0x80, 0]);
+            socket.write_all(&[5, 0x80]).await.unwrap();
+
+            let token = socket.read_u8().await.unwrap();
+            socket.write_u8(token).await.unwrap();
+
+            let mut request = [0; 10];
+            socket.read_exact(&mut request).await.unwrap();
+            socket
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80])
+                .await
+                .unwrap();
+        });
+
+        let methods: [&dyn AuthMethod; 2] = [&Token(42), &NoAuth];
+        let stream = Socks5Stream::connect_with_methods(
+            proxy,
+            ("127.0.0.1", 80),
+            &methods,
+            &ConnectOptions::default(),
+        )
+        .await;
+        assert_eq!(stream.unwrap().auth_method(), 0x80);
+    }
+
+    #[tokio::test]
+    async fn strict_policy_refuses_downgrade() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            socket.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 2]);
+            socket.write_all(&[5, 0]).await.unwrap();
+        });
+
+        let auth = Authentication::Password {
+            username: "user".to_string(),
+            password: "pass".to_string(),
+        };
+        let options = ConnectOptions {
+            auth_policy: AuthPolicy::RequireConfigured,
+            ..ConnectOptions::default()
+        };
+        let result =
+            Socks5Stream::connect_with_options(proxy, ("127.0.0.1", 80), &auth, &options).await;
+        assert!(matches!(result, Err(Socks5Error::AuthDowngraded)));
+    }
+}
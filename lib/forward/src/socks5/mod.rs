@@ -1,19 +1,59 @@
 mod addr;
 mod auth;
+mod bind;
+mod error;
 mod internal;
+mod method;
+mod resolve;
+mod session;
+mod udp;
+pub use bind::Socks5Listener;
+pub use error::Socks5Error;
 pub use internal::forward_tcp_to_socks5;
+pub use method::{AuthMethod, AuthPolicy, AuthStream, NoAuth, PasswordAuth};
+pub use resolve::{resolve, resolve_ptr};
+pub use session::Socks5Session;
+pub use udp::Socks5Datagram;
 
-use self::addr::{read_response, write_addr};
 use super::auth::Authentication;
-use super::target_addr::{TargetAddr, ToTargetAddr};
-use std::io;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use super::target_addr::{Resolution, TargetAddr, ToTargetAddr};
+use std::future::Future;
+use std::time::Duration;
 use tokio::net::TcpStream;
 use tokio::net::ToSocketAddrs;
+use tokio::time::timeout;
 
-pub struct Socks5Stream {
-    socket: TcpStream,
+/// Deadlines for the phases of establishing a `Socks5Stream`. `None` waits
+/// forever.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Timeouts {
+    /// TCP connect to the proxy.
+    pub connect: Option<Duration>,
+
+    /// Method selection and authentication.
+    pub handshake: Option<Duration>,
+
+    /// Sending the request and reading the proxy's reply.
+    pub reply: Option<Duration>,
+}
+
+/// Options for establishing a `Socks5Stream`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConnectOptions {
+    /// Where a domain name target is resolved.
+    pub resolution: Resolution,
+    pub timeouts: Timeouts,
+
+    /// Whether the proxy may skip the configured credentials.
+    pub auth_policy: AuthPolicy,
+}
+
+/// A connection through a SOCKS5 proxy, over plain TCP unless `S` says
+/// otherwise.
+pub struct Socks5Stream<S = TcpStream> {
+    socket: S,
     proxy_addr: TargetAddr,
+    auth_method: u8,
 }
 
 impl Socks5Stream {
@@ -22,7 +62,7 @@ impl Socks5Stream {
         proxy: T,
         target: U,
         auth: &Authentication,
-    ) -> io::Result<Socks5Stream>
+    ) -> Result<Socks5Stream, Socks5Error>
     where
         T: ToSocketAddrs,
         U: ToTargetAddr,
@@ -30,6 +70,21 @@ impl Socks5Stream {
         Self::connect_raw(1, proxy, target, auth).await
     }
 
+    /// Connects to a target server through a SOCKS5 proxy with the given
+    /// options.
+    pub async fn connect_with_options<T, U>(
+        proxy: T,
+        target: U,
+        auth: &Authentication,
+        options: &ConnectOptions,
+    ) -> Result<Socks5Stream, Socks5Error>
+    where
+        T: ToSocketAddrs,
+        U: ToTargetAddr,
+    {
+        Self::connect_raw_with_options(1, proxy, target, auth, options).await
+    }
+
     /// Connects to a target server through a SOCKS5 proxy using given
     /// username and password.
     pub async fn connect_with_password<T, U>(
@@ -37,7 +92,7 @@ impl Socks5Stream {
         target: U,
         username: &str,
         password: &str,
-    ) -> io::Result<Socks5Stream>
+    ) -> Result<Socks5Stream, Socks5Error>
     where
         T: ToSocketAddrs,
         U: ToTargetAddr,
@@ -49,89 +104,161 @@ impl Socks5Stream {
         Self::connect_raw(1, proxy, target, &auth).await
     }
 
+    /// Connects to a target server through a SOCKS5 proxy, offering the
+    /// given auth methods in order of preference.
+    pub async fn connect_with_methods<T, U>(
+        proxy: T,
+        target: U,
+        methods: &[&dyn AuthMethod],
+        options: &ConnectOptions,
+    ) -> Result<Socks5Stream, Socks5Error>
+    where
+        T: ToSocketAddrs,
+        U: ToTargetAddr,
+    {
+        Self::request(1, proxy, target, methods, options).await
+    }
+
     pub async fn connect_raw<T, U>(
         command: u8,
         proxy: T,
         target: U,
         auth: &Authentication,
-    ) -> io::Result<Socks5Stream>
+    ) -> Result<Socks5Stream, Socks5Error>
     where
         T: ToSocketAddrs,
         U: ToTargetAddr,
     {
-        let mut socket = TcpStream::connect(proxy).await?;
-
-        let target = target.to_target_addr()?;
-
-        let packet_len = if auth.is_no_auth() { 3 } else { 4 };
-
-        let packet = [
-            5,                                     // protocol version
-            if auth.is_no_auth() { 1 } else { 2 }, // method count
-            auth.id(),                             // method
-            0,                                     // no auth (always offered)
-        ];
-
-        socket.write_all(&packet[..packet_len]).await?;
-
-        let mut buf = [0; 2];
-        socket.read_exact(&mut buf).await?;
-        let response_version = buf[0];
-        let selected_method = buf[1];
-
-        if response_version != 5 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "invalid response version",
-            ));
-        }
-
-        if selected_method == 0xff {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "no acceptable auth methods",
-            ));
-        }
-
-        if selected_method != auth.id() && selected_method != Authentication::None.id() {
-            return Err(io::Error::new(io::ErrorKind::Other, "unknown auth method"));
-        }
-
-        match auth {
-            Authentication::Password { username, password } if selected_method == auth.id() => {
-                auth::password_authentication(&mut socket, username, password).await?
-            }
-            _ => (),
-        }
+        Self::connect_raw_with_options(command, proxy, target, auth, &ConnectOptions::default())
+            .await
+    }
 
-        write_addr(&mut socket, command, &target).await?;
+    /// Like `connect_raw`, with the given options.
+    pub async fn connect_raw_with_options<T, U>(
+        command: u8,
+        proxy: T,
+        target: U,
+        auth: &Authentication,
+        options: &ConnectOptions,
+    ) -> Result<Socks5Stream, Socks5Error>
+    where
+        T: ToSocketAddrs,
+        U: ToTargetAddr,
+    {
+        let methods = method::methods_for(auth, options.auth_policy);
+        let methods: Vec<&dyn AuthMethod> = methods.iter().map(|m| m.as_ref()).collect();
+        Self::request(command, proxy, target, &methods, options).await
+    }
 
-        let proxy_addr = read_response(&mut socket).await?;
+    async fn request<T, U>(
+        command: u8,
+        proxy: T,
+        target: U,
+        methods: &[&dyn AuthMethod],
+        options: &ConnectOptions,
+    ) -> Result<Socks5Stream, Socks5Error>
+    where
+        T: ToSocketAddrs,
+        U: ToTargetAddr,
+    {
+        let target = target.to_target_addr()?;
+        let target = target.resolve(options.resolution).await?;
 
-        Ok(Socks5Stream {
-            socket: socket,
-            proxy_addr: proxy_addr,
-        })
+        Socks5Session::open_with_methods(proxy, methods, options)
+            .await?
+            .request(command, target, options)
+            .await
     }
+}
 
+impl<S> Socks5Stream<S> {
     /// Returns the proxy-side address of the connection between the proxy and
     /// target server.
     pub fn proxy_addr(&self) -> &TargetAddr {
         &self.proxy_addr
     }
 
-    /// Returns a shared reference to the inner `TcpStream`.
-    pub fn get_ref(&self) -> &TcpStream {
+    /// Returns the id of the auth method the proxy selected, 0 if it skipped
+    /// authentication.
+    pub fn auth_method(&self) -> u8 {
+        self.auth_method
+    }
+
+    /// Returns a shared reference to the inner stream.
+    pub fn get_ref(&self) -> &S {
         &self.socket
     }
 
-    /// Returns a mutable reference to the inner `TcpStream`.
-    pub fn get_mut(&mut self) -> &mut TcpStream {
+    /// Returns a mutable reference to the inner stream.
+    pub fn get_mut(&mut self) -> &mut S {
         &mut self.socket
     }
 
-    /// Consumes the `Socks5Stream`, returning the inner `TcpStream`.
-    pub fn into_inner(self) -> TcpStream {
+    /// Consumes the `Socks5Stream`, returning the inner stream.
+    pub fn into_inner(self) -> S {
         self.socket
     }
 }
+
+/// Runs one phase of the handshake, failing with `Socks5Error::Timeout` if it
+/// takes longer than `limit`.
+async fn within<T, E, F>(
+    limit: Option<Duration>,
+    phase: &'static str,
+    future: F,
+) -> Result<T, Socks5Error>
+where
+    F: Future<Output = Result<T, E>>,
+    Socks5Error: From<E>,
+{
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return Ok(future.await?),
+    };
+    match timeout(limit, future).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(Socks5Error::Timeout { phase, limit }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn silent_proxy_times_out() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (_socket, _) = listener.accept().await.unwrap();
+            tokio::time::delay_for(Duration::from_secs(5)).await;
+        });
+
+        let options = ConnectOptions {
+            timeouts: Timeouts {
+                handshake: Some(Duration::from_millis(50)),
+                ..Timeouts::default()
+            },
+            ..ConnectOptions::default()
+        };
+        let target = ("127.0.0.1", 80).to_target_addr().unwrap();
+        let error = match Socks5Stream::connect_with_options(
+            proxy,
+            target,
+            &Authentication::None,
+            &options,
+        )
+        .await
+        {
+            Ok(_) => panic!("connected through a silent proxy"),
+            Err(error) => error,
+        };
+        match &error {
+            Socks5Error::Timeout { phase, .. } => assert_eq!(*phase, "handshake"),
+            error => panic!("unexpected error: {}", error),
+        }
+        assert_eq!(io::Error::from(error).kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,96 @@
+use super::{Socks5Error, Socks5Stream};
+use crate::auth::Authentication;
+use crate::target_addr::TargetAddr;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::ToSocketAddrs;
+
+/// Tor's extension command to resolve a hostname.
+const RESOLVE: u8 = 0xf0;
+
+/// Tor's extension command to resolve an IP address to a hostname.
+const RESOLVE_PTR: u8 = 0xf1;
+
+/// Resolves `domain` to an IP address through the proxy, which must support
+/// Tor's RESOLVE extension. Nothing is sent to the local resolver.
+pub async fn resolve<T: ToSocketAddrs>(
+    proxy: T,
+    domain: &str,
+    auth: &Authentication,
+) -> Result<IpAddr, Socks5Error> {
+    let target = TargetAddr::Domain(domain.to_string(), 0);
+    let stream = Socks5Stream::connect_raw(RESOLVE, proxy, target, auth).await?;
+    match stream.proxy_addr() {
+        TargetAddr::Ip(addr) => Ok(addr.ip()),
+        TargetAddr::Domain(..) => Err(Socks5Error::Protocol("RESOLVE answered a hostname")),
+    }
+}
+
+/// Resolves `ip` to a hostname through the proxy, which must support Tor's
+/// RESOLVE_PTR extension.
+pub async fn resolve_ptr<T: ToSocketAddrs>(
+    proxy: T,
+    ip: IpAddr,
+    auth: &Authentication,
+) -> Result<String, Socks5Error> {
+    let target = TargetAddr::Ip(SocketAddr::new(ip, 0));
+    let stream = Socks5Stream::connect_raw(RESOLVE_PTR, proxy, target, auth).await?;
+    match stream.proxy_addr() {
+        TargetAddr::Domain(domain, _) => Ok(domain.clone()),
+        TargetAddr::Ip(_) => Err(Socks5Error::Protocol("RESOLVE_PTR answered an IP address")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Runs a proxy that checks the request and sends `reply`.
+    async fn fake_proxy(request: &'static [u8], reply: &'static [u8]) -> SocketAddr {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            socket.read_exact(&mut greeting).await.unwrap();
+            socket.write_all(&[5, 0]).await.unwrap();
+
+            let mut received = vec![0; request.len()];
+            socket.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, request);
+            socket.write_all(reply).await.unwrap();
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn resolve_hostname() {
+        let proxy = fake_proxy(
+            b"\x05\xf0\x00\x03\x0bexample.com\x00\x00",
+            &[5, 0, 0, 1, 93, 184, 216, 34, 0, 0],
+        )
+        .await;
+        let ip = resolve(proxy, "example.com", &Authentication::None)
+            .await
+            .unwrap();
+        assert_eq!(ip, IpAddr::from([93, 184, 216, 34]));
+    }
+
+    #[tokio::test]
+    async fn resolve_ip() {
+        let proxy = fake_proxy(
+            &[5, 0xf1, 0, 1, 93, 184, 216, 34, 0, 0],
+            b"\x05\x00\x00\x03\x0bexample.com\x00\x00",
+        )
+        .await;
+        let name = resolve_ptr(
+            proxy,
+            IpAddr::from([93, 184, 216, 34]),
+            &Authentication::None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(name, "example.com");
+    }
+}
@@ -0,0 +1,124 @@
+use super::addr::{read_response, write_addr};
+use super::method::{self, AuthMethod, AuthStream};
+use super::{within, ConnectOptions, Socks5Error, Socks5Stream};
+use crate::auth::Authentication;
+use crate::happy_eyeballs;
+use crate::target_addr::ToTargetAddr;
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// A connection to a SOCKS5 proxy that went through method negotiation and
+/// authentication, and waits for its one request.
+///
+/// Opening sessions ahead of time saves the handshake round trips when the
+/// request is known. Sessions over another transport than plain TCP, such as
+/// TLS, start with `handshake`.
+#[derive(Debug)]
+pub struct Socks5Session<S = TcpStream> {
+    socket: S,
+    auth_method: u8,
+}
+
+impl Socks5Session {
+    /// Connects to the proxy and authenticates with `auth`.
+    ///
+    /// The connect and handshake timeouts of `options` apply.
+    pub async fn open<T: ToSocketAddrs>(
+        proxy: T,
+        auth: &Authentication,
+        options: &ConnectOptions,
+    ) -> Result<Socks5Session, Socks5Error> {
+        let methods = method::methods_for(auth, options.auth_policy);
+        let methods: Vec<&dyn AuthMethod> = methods.iter().map(|m| m.as_ref()).collect();
+        Self::open_with_methods(proxy, &methods, options).await
+    }
+
+    /// Connects to the proxy, offering the given auth methods in order of
+    /// preference.
+    pub async fn open_with_methods<T: ToSocketAddrs>(
+        proxy: T,
+        methods: &[&dyn AuthMethod],
+        options: &ConnectOptions,
+    ) -> Result<Socks5Session, Socks5Error> {
+        let socket = within(
+            options.timeouts.connect,
+            "connect",
+            happy_eyeballs::connect(proxy),
+        )
+        .await?;
+        Self::handshake_with_methods(socket, methods, options).await
+    }
+}
+
+impl<S: AuthStream> Socks5Session<S> {
+    /// Authenticates with `auth` over `stream`, already connected to the
+    /// proxy.
+    ///
+    /// The handshake timeout of `options` applies.
+    pub async fn handshake(
+        stream: S,
+        auth: &Authentication,
+        options: &ConnectOptions,
+    ) -> Result<Socks5Session<S>, Socks5Error> {
+        let methods = method::methods_for(auth, options.auth_policy);
+        let methods: Vec<&dyn AuthMethod> = methods.iter().map(|m| m.as_ref()).collect();
+        Self::handshake_with_methods(stream, &methods, options).await
+    }
+
+    /// Like `handshake`, offering the given auth methods in order of
+    /// preference.
+    pub async fn handshake_with_methods(
+        mut stream: S,
+        methods: &[&dyn AuthMethod],
+        options: &ConnectOptions,
+    ) -> Result<Socks5Session<S>, Socks5Error> {
+        let auth_method = within(
+            options.timeouts.handshake,
+            "handshake",
+            method::negotiate(&mut stream, methods),
+        )
+        .await?;
+        Ok(Socks5Session {
+            socket: stream,
+            auth_method,
+        })
+    }
+
+    /// Returns the id of the auth method the proxy selected.
+    pub fn auth_method(&self) -> u8 {
+        self.auth_method
+    }
+
+    /// Asks the proxy to connect to `target`.
+    ///
+    /// The resolution and reply timeout of `options` apply.
+    pub async fn connect<U: ToTargetAddr>(
+        self,
+        target: U,
+        options: &ConnectOptions,
+    ) -> Result<Socks5Stream<S>, Socks5Error> {
+        self.request(1, target, options).await
+    }
+
+    pub(crate) async fn request<U: ToTargetAddr>(
+        mut self,
+        command: u8,
+        target: U,
+        options: &ConnectOptions,
+    ) -> Result<Socks5Stream<S>, Socks5Error> {
+        let target = target.to_target_addr()?;
+        let target = target.resolve(options.resolution).await?;
+
+        let socket = &mut self.socket;
+        let proxy_addr = within(options.timeouts.reply, "reply", async {
+            write_addr(socket, command, &target).await?;
+            read_response(socket).await
+        })
+        .await?;
+
+        Ok(Socks5Stream {
+            socket: self.socket,
+            proxy_addr,
+            auth_method: self.auth_method,
+        })
+    }
+}
@@ -0,0 +1,240 @@
+use super::addr::{read_addr, write_addr_to_packet, MAX_ADDR_LEN};
+use super::{ConnectOptions, Socks5Error, Socks5Stream};
+use crate::auth::Authentication;
+use crate::target_addr::{TargetAddr, ToTargetAddr};
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::{TcpStream, ToSocketAddrs, UdpSocket};
+
+/// The largest payload of a UDP datagram.
+const MAX_DATAGRAM_LEN: usize = 65535;
+
+/// A UDP socket relaying datagrams through a SOCKS5 proxy.
+///
+/// The association lives as long as the control TCP connection to the proxy,
+/// which is kept open until this value is dropped.
+pub struct Socks5Datagram {
+    socket: UdpSocket,
+    control: TcpStream,
+    relay_addr: SocketAddr,
+    auth_method: u8,
+    buf: Vec<u8>,
+}
+
+impl Socks5Datagram {
+    /// Binds a UDP socket to `local` and asks the proxy to relay its
+    /// datagrams (UDP ASSOCIATE).
+    pub async fn bind<T, U>(
+        proxy: T,
+        local: U,
+        auth: &Authentication,
+    ) -> Result<Socks5Datagram, Socks5Error>
+    where
+        T: ToSocketAddrs,
+        U: ToSocketAddrs,
+    {
+        Self::bind_with_options(proxy, local, auth, &ConnectOptions::default()).await
+    }
+
+    /// Like `bind`, with the given options for the control connection.
+    pub async fn bind_with_options<T, U>(
+        proxy: T,
+        local: U,
+        auth: &Authentication,
+        options: &ConnectOptions,
+    ) -> Result<Socks5Datagram, Socks5Error>
+    where
+        T: ToSocketAddrs,
+        U: ToSocketAddrs,
+    {
+        let socket = UdpSocket::bind(local).await?;
+
+        // The address datagrams will come from. The local one may be a
+        // loopback or NAT address the proxy never sees, so an unspecified one
+        // is sent to have the proxy accept them from the client's real
+        // address (RFC 1928).
+        let from = match socket.local_addr()? {
+            SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+            SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+        };
+        let stream = Socks5Stream::connect_raw_with_options(3, proxy, from, auth, options).await?;
+
+        let auth_method = stream.auth_method();
+        let relay_addr = stream.proxy_addr().clone();
+        let control = stream.into_inner();
+
+        // Some proxies answer with an unspecified address, meaning the
+        // address the control connection went to. A domain name is taken to
+        // mean the same rather than looked up here, where it may resolve to
+        // another host than the proxy.
+        let relay_addr = match relay_addr {
+            TargetAddr::Ip(addr) if !addr.ip().is_unspecified() => addr,
+            TargetAddr::Ip(addr) => SocketAddr::new(control.peer_addr()?.ip(), addr.port()),
+            TargetAddr::Domain(_, port) => SocketAddr::new(control.peer_addr()?.ip(), port),
+        };
+
+        Ok(Socks5Datagram {
+            socket,
+            control,
+            relay_addr,
+            auth_method,
+            buf: vec![0; MAX_DATAGRAM_LEN],
+        })
+    }
+
+    /// Sends `data` to `target` through the proxy and returns its length.
+    pub async fn send_to<A: ToTargetAddr>(&mut self, data: &[u8], target: A) -> io::Result<usize> {
+        let packet = pack(&target.to_target_addr()?, data)?;
+        self.socket.send_to(&packet, &self.relay_addr).await?;
+        Ok(data.len())
+    }
+
+    /// Receives a datagram relayed by the proxy, returning its length and
+    /// where it came from.
+    ///
+    /// Datagrams that do not come from the relay, are malformed or are
+    /// fragments are dropped.
+    pub async fn recv_from(&mut self, data: &mut [u8]) -> io::Result<(usize, TargetAddr)> {
+        loop {
+            let (len, from) = self.socket.recv_from(&mut self.buf).await?;
+            if from != self.relay_addr {
+                continue;
+            }
+            match unpack(&self.buf[..len]).await {
+                Ok(Some((source, payload))) => {
+                    let len = payload.len().min(data.len());
+                    data[..len].copy_from_slice(&payload[..len]);
+                    return Ok((len, source));
+                }
+                Ok(None) => eprintln!("Dropping fragmented datagram from {}", from),
+                Err(error) => eprintln!("Dropping malformed datagram from {}: {}", from, error),
+            }
+        }
+    }
+
+    /// Returns the address of the proxy's UDP relay.
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.relay_addr
+    }
+
+    /// Returns a shared reference to the local `UdpSocket`.
+    pub fn get_ref(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Returns the id of the auth method the proxy selected for the control
+    /// connection.
+    pub fn auth_method(&self) -> u8 {
+        self.auth_method
+    }
+
+    /// Returns a shared reference to the control `TcpStream`.
+    pub fn control(&self) -> &TcpStream {
+        &self.control
+    }
+}
+
+/// Prepends the RFC 1928 UDP request header to `data`.
+fn pack(target: &TargetAddr, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut packet = vec![0; 3 + MAX_ADDR_LEN + data.len()];
+    // Reserved (2 bytes) and fragment number, always 0: no fragmentation.
+    let len = 3 + write_addr_to_packet(&mut packet[3..], target)?;
+    packet.truncate(len);
+    packet.extend_from_slice(data);
+    Ok(packet)
+}
+
+/// Splits a datagram from the relay into its source and payload, or `None`
+/// if it is a fragment.
+async fn unpack(mut packet: &[u8]) -> io::Result<Option<(TargetAddr, &[u8])>> {
+    if packet.len() < 3 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "datagram too short",
+        ));
+    }
+    if packet[2] != 0 {
+        return Ok(None);
+    }
+
+    packet = &packet[3..];
+    let source = read_addr(&mut packet).await?;
+    Ok(Some((source, packet)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn header_round_trip() {
+        let target = ("example.com", 53).to_target_addr().unwrap();
+        let packet = pack(&target, b"query").unwrap();
+        assert_eq!(&packet[..5], &[0, 0, 0, 3, 11]);
+
+        let (source, payload) = unpack(&packet).await.unwrap().unwrap();
+        assert_eq!(source, target);
+        assert_eq!(payload, b"query");
+    }
+
+    #[tokio::test]
+    async fn fragments_are_rejected() {
+        let target = ("127.0.0.1", 53).to_target_addr().unwrap();
+        let mut packet = pack(&target, b"part").unwrap();
+        packet[2] = 1;
+        assert!(unpack(&packet).await.unwrap().is_none());
+        assert!(unpack(&[0, 0]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn associate_sends_unspecified_addr() {
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            socket.read_exact(&mut greeting).await.unwrap();
+            socket.write_all(&[5, 0]).await.unwrap();
+
+            let mut request = [0; 10];
+            socket.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, [5, 3, 0, 1, 0, 0, 0, 0, 0, 0]);
+            socket
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 53])
+                .await
+                .unwrap();
+            let _ = socket.read_u8().await;
+        });
+
+        let datagram = Socks5Datagram::bind(proxy, "127.0.0.1:0", &Authentication::None)
+            .await
+            .unwrap();
+        assert_eq!(datagram.relay_addr, "127.0.0.1:53".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn domain_relay_is_the_proxy() {
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            socket.read_exact(&mut greeting).await.unwrap();
+            socket.write_all(&[5, 0]).await.unwrap();
+
+            let mut request = [0; 10];
+            socket.read_exact(&mut request).await.unwrap();
+            let mut reply = vec![5, 0, 0, 3, 13];
+            reply.extend_from_slice(b"relay.invalid");
+            reply.extend_from_slice(&[0, 53]);
+            socket.write_all(&reply).await.unwrap();
+            let _ = socket.read_u8().await;
+        });
+
+        let datagram = Socks5Datagram::bind(proxy, "127.0.0.1:0", &Authentication::None)
+            .await
+            .unwrap();
+        assert_eq!(datagram.relay_addr, "127.0.0.1:53".parse().unwrap());
+    }
+}
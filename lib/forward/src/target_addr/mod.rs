@@ -1,4 +1,6 @@
+mod resolve;
 mod str_to;
+pub use resolve::{AddressFamily, Resolution};
 
 use std::io;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
@@ -11,8 +13,8 @@ pub enum TargetAddr {
 
     /// Connect to a fully qualified domain name.
     ///
-    /// The domain name will be passed along to the proxy server and DNS lookup
-    /// will happen there.
+    /// By default the domain name will be passed along to the proxy server and
+    /// DNS lookup will happen there, see `Resolution`.
     Domain(String, u16),
 }
 
@@ -0,0 +1,107 @@
+use super::TargetAddr;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use tokio::net::lookup_host;
+
+/// An IP address family.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+/// Where the domain name of a `TargetAddr::Domain` is resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Resolution {
+    /// Pass the domain name along to the proxy, DNS lookup happens there.
+    ///
+    /// This is what `socks5h://` means.
+    #[default]
+    Remote,
+
+    /// Resolve the domain name locally and send the first address to the
+    /// proxy.
+    Local,
+
+    /// Resolve the domain name locally and send the first address of the
+    /// given family, or the first address if there is none of that family.
+    LocalPrefer(AddressFamily),
+}
+
+impl FromStr for Resolution {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Resolution> {
+        match s.to_ascii_lowercase().as_str() {
+            "remote" => Ok(Resolution::Remote),
+            "local" => Ok(Resolution::Local),
+            "local-ipv4" => Ok(Resolution::LocalPrefer(AddressFamily::Ipv4)),
+            "local-ipv6" => Ok(Resolution::LocalPrefer(AddressFamily::Ipv6)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unknown resolution, expected remote, local, local-ipv4 or local-ipv6",
+            )),
+        }
+    }
+}
+
+impl TargetAddr {
+    /// Applies the resolution policy to this address.
+    ///
+    /// IP addresses, and domain names with `Resolution::Remote`, are returned
+    /// unchanged.
+    pub async fn resolve(&self, resolution: Resolution) -> io::Result<TargetAddr> {
+        let (domain, port) = match (self, resolution) {
+            (TargetAddr::Ip(_), _) | (_, Resolution::Remote) => return Ok(self.clone()),
+            (TargetAddr::Domain(domain, port), _) => (domain.as_str(), *port),
+        };
+
+        let addrs: Vec<SocketAddr> = lookup_host((domain, port)).await?.collect();
+        pick_addr(&addrs, resolution)
+            .map(TargetAddr::Ip)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no addresses found for {}", domain),
+                )
+            })
+    }
+}
+
+fn pick_addr(addrs: &[SocketAddr], resolution: Resolution) -> Option<SocketAddr> {
+    let preferred = match resolution {
+        Resolution::LocalPrefer(AddressFamily::Ipv4) => addrs.iter().find(|a| a.is_ipv4()),
+        Resolution::LocalPrefer(AddressFamily::Ipv6) => addrs.iter().find(|a| a.is_ipv6()),
+        _ => None,
+    };
+    preferred.or_else(|| addrs.first()).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pick_preferred_family() {
+        let addrs: Vec<SocketAddr> =
+            vec!["[::1]:80".parse().unwrap(), "127.0.0.1:80".parse().unwrap()];
+
+        assert_eq!(pick_addr(&addrs, Resolution::Local), Some(addrs[0]));
+        assert_eq!(
+            pick_addr(&addrs, Resolution::LocalPrefer(AddressFamily::Ipv4)),
+            Some(addrs[1])
+        );
+        assert_eq!(
+            pick_addr(&addrs[..1], Resolution::LocalPrefer(AddressFamily::Ipv4)),
+            Some(addrs[0])
+        );
+        assert_eq!(pick_addr(&[], Resolution::Local), None);
+    }
+
+    #[tokio::test]
+    async fn remote_keeps_domain() {
+        let target = TargetAddr::Domain("example.invalid".to_string(), 80);
+        assert_eq!(target.resolve(Resolution::Remote).await.unwrap(), target);
+    }
+}
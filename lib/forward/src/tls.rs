@@ -0,0 +1,307 @@
+//! TLS for the connection to the proxy, with the `tls` feature.
+//!
+//! A `Socks5Session` runs over the TLS stream like over plain TCP:
+//!
+//! ```no_run
+//! # async fn run(config: forward::tls::TlsConfig) -> std::io::Result<()> {
+//! use forward::auth::Authentication;
+//! use forward::socks5::{ConnectOptions, Socks5Session};
+//! use tokio::net::TcpStream;
+//!
+//! let socket = TcpStream::connect("proxy.example:1080").await?;
+//! let tls = config.connect(socket, "proxy.example").await?;
+//! let options = ConnectOptions::default();
+//! let stream = Socks5Session::handshake(tls, &Authentication::None, &options)
+//!     .await?
+//!     .connect(("example.com", 443), &options)
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use ring::digest::{digest, SHA256};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, PrivateKey, RootCertStore, ServerCertVerified, ServerCertVerifier,
+    TLSError, WebPKIVerifier,
+};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+
+/// How to set up TLS with the proxy.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TlsOptions {
+    /// The name sent with SNI and checked against the certificate, the proxy
+    /// host by default. Required for a proxy given by IP address.
+    pub server_name: Option<String>,
+
+    /// PEM file with the CA certificates to trust instead of the built-in
+    /// web PKI roots.
+    pub ca_file: Option<PathBuf>,
+
+    /// PEM files of a client certificate chain and its private key, sent
+    /// when the proxy asks for one.
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+
+    /// SHA-256 digests of the DER-encoded proxy certificate. If not empty,
+    /// the certificate must match one of them on top of being valid.
+    pub pins: Vec<[u8; 32]>,
+}
+
+/// A TLS client setup for proxy connections, built once from `TlsOptions`.
+///
+/// Two configs are equal if they were built from equal options.
+#[derive(Clone)]
+pub struct TlsConfig {
+    options: TlsOptions,
+    connector: TlsConnector,
+}
+
+impl TlsConfig {
+    /// Reads the certificate files of `options`.
+    pub fn new(options: TlsOptions) -> io::Result<TlsConfig> {
+        let mut config = ClientConfig::new();
+        match &options.ca_file {
+            Some(path) => {
+                let pem = fs::read(path)?;
+                let (added, _) = config
+                    .root_store
+                    .add_pem_file(&mut pem.as_slice())
+                    .map_err(|()| invalid("invalid CA bundle"))?;
+                if added == 0 {
+                    return Err(invalid("no CA certificate in bundle"));
+                }
+            }
+            None => config
+                .root_store
+                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+        }
+
+        if let Some((cert, key)) = &options.client_cert {
+            let certs = pemfile::certs(&mut fs::read(cert)?.as_slice())
+                .map_err(|()| invalid("invalid client certificate"))?;
+            let key = read_private_key(&fs::read(key)?)?;
+            config
+                .set_single_client_cert(certs, key)
+                .map_err(|e| invalid(&e.to_string()))?;
+        }
+
+        if !options.pins.is_empty() {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(PinnedVerifier {
+                    inner: WebPKIVerifier::new(),
+                    pins: options.pins.clone(),
+                }));
+        }
+
+        Ok(TlsConfig {
+            options,
+            connector: TlsConnector::from(Arc::new(config)),
+        })
+    }
+
+    pub fn options(&self) -> &TlsOptions {
+        &self.options
+    }
+
+    /// Runs the TLS handshake over `stream`, connected to the proxy at
+    /// `host`.
+    pub async fn connect(&self, stream: TcpStream, host: &str) -> io::Result<TlsStream<TcpStream>> {
+        let name = self.options.server_name.as_deref().unwrap_or(host);
+        let name = DNSNameRef::try_from_ascii_str(name)
+            .map_err(|_| invalid("invalid TLS server name, an IP address needs one set"))?;
+        self.connector.connect(name, stream).await
+    }
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("TlsConfig").field(&self.options).finish()
+    }
+}
+
+impl PartialEq for TlsConfig {
+    fn eq(&self, other: &TlsConfig) -> bool {
+        self.options == other.options
+    }
+}
+
+/// Parses a certificate pin, the hex SHA-256 digest of the DER-encoded
+/// certificate, optionally with colons between bytes.
+pub fn parse_pin(s: &str) -> io::Result<[u8; 32]> {
+    let hex: Vec<u8> = s.bytes().filter(|b| *b != b':').collect();
+    let mut pin = [0; 32];
+    if hex.len() != pin.len() * 2 {
+        return Err(invalid("a certificate pin has 32 bytes"));
+    }
+    for (byte, digits) in pin.iter_mut().zip(hex.chunks(2)) {
+        *byte = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| invalid("invalid hex in certificate pin"))?;
+    }
+    Ok(pin)
+}
+
+/// Verifies the chain as usual, then the pin of the end-entity certificate.
+struct PinnedVerifier {
+    inner: WebPKIVerifier,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let verified =
+            self.inner
+                .verify_server_cert(roots, presented_certs, dns_name, ocsp_response)?;
+        let cert = presented_certs
+            .first()
+            .ok_or(TLSError::NoCertificatesPresented)?;
+        let hash = digest(&SHA256, &cert.0);
+        if self.pins.iter().any(|pin| pin[..] == *hash.as_ref()) {
+            Ok(verified)
+        } else {
+            Err(TLSError::General(
+                "proxy certificate matches no pin".to_string(),
+            ))
+        }
+    }
+}
+
+/// Reads the first PKCS#8 or RSA private key of a PEM file.
+fn read_private_key(pem: &[u8]) -> io::Result<PrivateKey> {
+    let keys =
+        pemfile::pkcs8_private_keys(&mut &pem[..]).map_err(|()| invalid("invalid private key"))?;
+    let keys = if keys.is_empty() {
+        pemfile::rsa_private_keys(&mut &pem[..]).map_err(|()| invalid("invalid private key"))?
+    } else {
+        keys
+    };
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| invalid("no private key in file"))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
+    use tokio_rustls::TlsAcceptor;
+
+    /// Returns a file of `testdata/tls`: two self-signed P-256 CAs and a
+    /// certificate for `proxy.test` issued by `ca.pem`, valid until 2126.
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/tls")
+            .join(name)
+    }
+
+    /// Runs a TLS server for `proxy.test` that answers `ping` with `pong`.
+    /// Returns its address and the pin of its certificate.
+    async fn server() -> (std::net::SocketAddr, [u8; 32]) {
+        let certs =
+            pemfile::certs(&mut fs::read(testdata("proxy.pem")).unwrap().as_slice()).unwrap();
+        let key = read_private_key(&fs::read(testdata("proxy-key.pem")).unwrap()).unwrap();
+        let mut pin = [0; 32];
+        pin.copy_from_slice(digest(&SHA256, &certs[0].0).as_ref());
+
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.set_single_cert(certs, key).unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    // Failing handshakes are what some tests expect.
+                    if let Ok(mut stream) = acceptor.accept(socket).await {
+                        let mut buf = [0; 4];
+                        stream.read_exact(&mut buf).await.unwrap();
+                        stream.write_all(b"pong").await.unwrap();
+                    }
+                });
+            }
+        });
+        (addr, pin)
+    }
+
+    async fn connect(
+        addr: std::net::SocketAddr,
+        ca: &str,
+        pins: Vec<[u8; 32]>,
+    ) -> io::Result<TlsStream<TcpStream>> {
+        let config = TlsConfig::new(TlsOptions {
+            server_name: Some("proxy.test".to_string()),
+            ca_file: Some(testdata(ca)),
+            pins,
+            ..TlsOptions::default()
+        })?;
+        let socket = TcpStream::connect(addr).await?;
+        let mut stream = config.connect(socket, "127.0.0.1").await?;
+        stream.write_all(b"ping").await?;
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"pong");
+        Ok(stream)
+    }
+
+    #[tokio::test]
+    async fn trusted_ca() {
+        let (addr, _) = server().await;
+        assert!(connect(addr, "ca.pem", vec![]).await.is_ok());
+        assert!(connect(addr, "other-ca.pem", vec![]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn pinned_certificate() {
+        let (addr, pin) = server().await;
+        assert!(connect(addr, "ca.pem", vec![[0; 32], pin]).await.is_ok());
+
+        let error = connect(addr, "ca.pem", vec![[0; 32]]).await.unwrap_err();
+        assert!(error.to_string().contains("matches no pin"));
+
+        // A pin does not replace the chain check.
+        assert!(connect(addr, "other-ca.pem", vec![pin]).await.is_err());
+    }
+
+    #[test]
+    fn parse_pins() {
+        let hex = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+        let pin = parse_pin(hex).unwrap();
+        assert_eq!(pin[1], 0x11);
+        assert_eq!(pin[31], 0xff);
+
+        let colons: Vec<String> = hex
+            .as_bytes()
+            .chunks(2)
+            .map(|c| String::from_utf8(c.to_vec()).unwrap())
+            .collect();
+        assert_eq!(parse_pin(&colons.join(":")).unwrap(), pin);
+
+        assert!(parse_pin("0011").is_err());
+        assert!(parse_pin(&hex.replace("ff", "zz")).is_err());
+    }
+}